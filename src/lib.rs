@@ -0,0 +1,3 @@
+pub mod modules;
+
+pub mod simulator;
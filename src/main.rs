@@ -1,10 +1,16 @@
-mod modules;
-
-mod simulator;
-use simulator::*;
+use nand::modules;
+use nand::simulator::*;
 
 type SimType = ChangeListSimulator;
-// clocks/s: 154k
+// clocks/s: 190k, with bench_settled: 178k
+
+//type SimType = LevelizedSimulator;
+// clocks/s: 182k, with bench_settled: 200k
+// about 4% behind ChangeList at a fixed 64 steps per half clock, and about 12% ahead when each
+// half clock only runs until the circuit settles
+
+//type SimType = EventSimulator;
+// clocks/s: 25k
 
 //type SimType = SimpleSimulator;
 // clocks/s: 3k
@@ -41,7 +47,7 @@ fn main() {
     }
 
     let (io, mut sim): (_, SimType) = build_simulator(|| {
-        use crate::simulator::v::*;
+        use nand::simulator::v::*;
 
         let (rst_i, rst) = input(1);
         let (clk_i, clk) = input(1);
@@ -98,8 +104,7 @@ fn main() {
         }
     });
 
-    let mut clock = 0u8;
-
+    sim.set(&io.spi_miso, 0u8);
     sim.set(&io.rst, 1u8);
     sim.step_until_settled(1000);
     sim.set(&io.rst, 0u8);
@@ -108,20 +113,20 @@ fn main() {
     //let (clocks, snaps, steps) = (80, 80, 1);
     let (clocks, snaps, steps) = (300, 100, 1000);
 
+    sim.set_clock(&io.clk);
+    sim.set_settle_limit(steps);
+
     let mut spi_clk_prev = 0u8;
     let mut spi_buf: u8 = 0;
     let mut spi_bit: usize = 0;
     let mut spi_output: Vec<u8> = Vec::new();
 
     for t in 0..clocks {
-        sim.set(&io.clk, clock);
-        clock = 1 - clock;
-
         if t < snaps {
             sim.snapshot();
         }
 
-        sim.step_by(128);
+        sim.clock_edge().expect("CPU did not settle");
 
         if sim.get::<u8>(&io.spi_cs) == 0u8 {
             let spi_clk: u8 = sim.get(&io.spi_clk);
@@ -148,5 +153,10 @@ fn main() {
     println!("SPI output: {:?}", spi_output);
     println!("SPI output: {:?}", String::from_utf8(spi_output));
 
+    // what the optimizer did to the benchmarked netlist, with -v
+    if std::env::args().any(|arg| arg == "-v" || arg == "--verbose") {
+        println!("{}", sim.netlist().optimizer);
+    }
+
     bench(&mut sim, io.clk);
 }
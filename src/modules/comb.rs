@@ -89,7 +89,7 @@ impl VVec {
     }
 
     fn combine(self, f: fn(V, V) -> V) -> V {
-        if self.len() == 0 {
+        if self.is_empty() {
             return zero();
         }
 
@@ -186,10 +186,10 @@ impl<T> VVecMatrix for T where T: IntoIterator<Item=VVec> {
     fn orm(self) -> VVec {
         let vvs = self.into_iter().collect::<Vec<VVec>>();
 
-        if vvs.len() == 0 {
+        if vvs.is_empty() {
             panic!("cannot orm a zero length list");
         } else if vvs.len() == 1 {
-            return vvs[0];
+            vvs[0]
         } else {
             (0..vvs[0].len())
                 .map(|index| {
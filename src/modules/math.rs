@@ -94,4 +94,40 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_adder_lanes() {
+        let ((x_i, y_i, c_i, r, carry), mut sim): (_, BitParallelSimulator) = build_simulator(|| {
+            let (x_i, x) = input(4);
+            let (y_i, y) = input(4);
+            let (c_i, c) = input(1);
+
+            let a = adder(x, y, c.at(0));
+            (x_i, y_i, c_i, a.0.output(), a.1.output())
+        });
+
+        let vectors: Vec<(u64, u64, u64)> = (0..=15)
+            .flat_map(|x| (0..=15).flat_map(move |y| (0..=1).map(move |c| (x, y, c))))
+            .collect();
+
+        for batch in vectors.chunks(LANES) {
+            for (lane, &(x, y, c)) in batch.iter().enumerate() {
+                sim.set_lane(&x_i, lane, x);
+                sim.set_lane(&y_i, lane, y);
+                sim.set_lane(&c_i, lane, c);
+            }
+
+            sim.step_until_settled(1000).expect("adder did not settle");
+
+            for (lane, &(x, y, c)) in batch.iter().enumerate() {
+                let expected_result = x + y + c;
+
+                let result: u64 = sim.get_lane(&r, lane);
+                let carry: u64 = sim.get_lane(&carry, lane);
+
+                assert_eq!(result, expected_result & 0x0f);
+                assert_eq!(carry == 1, (expected_result > 0x0f));
+            }
+        }
+    }
 }
@@ -20,3 +20,6 @@ mod cpu;
 pub use cpu::*;
 
 pub use crate::simulator::v::*;
+
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,72 @@
+use super::*;
+use crate::simulator::{Input, Output};
+
+pub struct CpuSystem {
+    pub rst: Input,
+    pub clk: Input,
+    pub addr: Output,
+    pub data: Output,
+    pub w: Output,
+}
+
+/// Small system with a `cpu()` running a counting loop from ROM, for testing simulators
+pub fn cpu_system() -> CpuSystem {
+    let rom_data: Vec<u64> = vec![
+        0x10, 0x01, // ldi r0, 1
+        0x11, 0x01, // ldi r1, 1
+        0x44,       // add r0, r1
+        0x34,       // str r0, r1
+        0x80, 0xfd, // jmp -3
+    ];
+
+    let (rst_i, rst) = input(1);
+    let (clk_i, clk) = input(1);
+
+    let data_bus = vv(8);
+
+    let c = cpu(CpuInputs {
+        data_bus,
+        clk: clk.at(0),
+        rst: rst.at(0),
+    });
+
+    data_bus << (c.data_bus_out | rom(8, &rom_data, c.addr_bus.slice(0..3), one()));
+
+    CpuSystem {
+        rst: rst_i,
+        clk: clk_i,
+        addr: c.addr_bus.output(),
+        data: c.data_bus_out.output(),
+        w: c.data_write.output(),
+    }
+}
+
+pub struct RamSystem {
+    pub rst: Input,
+    pub clk: Input,
+    pub addr: Input,
+    pub data: Input,
+    pub w: Input,
+    pub q: Output,
+}
+
+/// A `ram()` with `1 << addr_bits` bytes, for benchmarking simulators on a large netlist. Every
+/// word has its own edge detector on the clock, so each clock edge changes many gates at once.
+pub fn ram_system(addr_bits: usize) -> RamSystem {
+    let (rst_i, rst) = input(1);
+    let (clk_i, clk) = input(1);
+    let (addr_i, addr) = input(addr_bits);
+    let (data_i, data) = input(8);
+    let (w_i, w) = input(1);
+
+    let q = ram(1 << addr_bits, addr, data, w.at(0), one(), clk.at(0), !rst.at(0));
+
+    RamSystem {
+        rst: rst_i,
+        clk: clk_i,
+        addr: addr_i,
+        data: data_i,
+        w: w_i,
+        q: q.output(),
+    }
+}
@@ -0,0 +1,242 @@
+use std::io;
+
+use rayon::prelude::*;
+
+use crate::simulator::*;
+use crate::simulator::netlist::Netlist;
+use crate::simulator::bits::Bits;
+use crate::simulator::state_file::{StateReader, StateWriter};
+
+/// Number of independent copies of the circuit simulated at once
+pub const LANES: usize = 64;
+
+/// Simulates 64 independent copies of the circuit at once by packing one bit per copy ("lane")
+/// into a u64 per gate. Otherwise works like `SimpleSimulator`.
+///
+/// The plain `Simulator` methods set every lane to the same value and read lane 0. Use
+/// `set_lane` and `get_lane` to drive and observe the lanes separately.
+pub struct BitParallelSimulator {
+    cur_out: usize,
+    state: [Vec<u64>; 2],
+    forced: Bits,
+    netlist: Netlist,
+    traces: Vec<String>,
+    clock: Clock,
+}
+
+impl BitParallelSimulator {
+    /// Sets an input for a single lane, leaving other lanes as they are
+    pub fn set_lane(&mut self, input: &Input, lane: usize, bits: impl Into<u64>) {
+        assert!(lane < LANES, "lane out of range");
+
+        let bits = bits.into();
+        let mask = 1u64 << lane;
+
+        for (bit, id) in input.0.iter().copied().enumerate() {
+            let index = self.netlist.input_index(id);
+            let b = bits & (1 << bit) != 0;
+
+            if self.forced.get(index) != 0 {
+                continue;
+            }
+
+            for state in &mut self.state {
+                state[index] = (state[index] & !mask) | if b { mask } else { 0 };
+            }
+        }
+    }
+
+    /// Reads an output from a single lane
+    pub fn get_lane<R: TryFrom<u64>>(&self, output: &Output, lane: usize) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        assert!(lane < LANES, "lane out of range");
+
+        let mut r = 0u64;
+
+        for (bit, id) in output.0.iter().copied().enumerate() {
+            let index = self.netlist.output_index(id);
+            r |= ((self.state[self.cur_out][index] >> lane) & 1) << bit;
+        }
+
+        r.try_into().expect("output too long for data type")
+    }
+}
+
+impl Simulator for BitParallelSimulator {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+
+        BitParallelSimulator {
+            cur_out: 0,
+            state: [
+                vec![0; netlist.len()],
+                vec![0; netlist.len()]
+            ],
+            forced: Bits::new(netlist.len()),
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            netlist,
+        }
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        let bits = bits.into();
+
+        for (bit, id) in input.0.iter().copied().enumerate() {
+            let index = self.netlist.input_index(id);
+
+            if self.forced.get(index) != 0 {
+                continue;
+            }
+
+            let b = if bits & (1 << bit) != 0 { !0 } else { 0 };
+            self.state[self.cur_out][index] = b;
+            self.state[1 - self.cur_out][index] = b;
+        }
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        self.get_lane(output, 0)
+    }
+
+    /// Runs the simulation for one timestep
+    fn step(&mut self) {
+        self.cur_out = 1 - self.cur_out;
+
+        let state = self.state.split_at_mut(1);
+        let (state_in, state_out) = if self.cur_out == 0 {
+            (&state.1[0], &mut state.0[0])
+        } else {
+            (&state.0[0], &mut state.1[0])
+        };
+
+        let chunk_size = 256;
+        let forced = &self.forced;
+
+        state_out[self.netlist.n_inputs..]
+            .par_chunks_mut(chunk_size)
+            .enumerate()
+            .for_each(|(chunk_index, out)| {
+                let offset = chunk_index * chunk_size + self.netlist.n_inputs;
+
+                for (index, out) in out.iter_mut().enumerate() {
+                    if forced.get(index + offset) != 0 {
+                        continue;
+                    }
+
+                    let g = &self.netlist.gates[index + offset];
+                    *out = !(state_in[g.0 as usize] & state_in[g.1 as usize]);
+                }
+            });
+    }
+
+    /// Runs the simulation until every lane settles or a maximum number of timesteps. Returns the
+    /// number of steps if the simulation settled within the allotted number of steps, or None if it
+    /// didn't.
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        let mut i = 0;
+        while i < max_steps {
+            i += 1;
+
+            self.step();
+
+            if self.state[0] == self.state[1] {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            let v = self.state[self.cur_out][*index] & 1 != 0;
+            out.push(if v { '█' } else { '▁' })
+        }
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        let mut w = StateWriter::new("BitParallelSimulator", self.netlist.fingerprint());
+        w.u64(self.cur_out as u64);
+        w.u64s(&self.state[0]);
+        w.u64s(&self.state[1]);
+        w.bits(&self.forced);
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = self.netlist.len();
+
+        let mut r = StateReader::new(bytes, "BitParallelSimulator", self.netlist.fingerprint())?;
+        let cur_out = (r.u64()? != 0) as usize;
+        let state = [r.u64s_len(len)?, r.u64s_len(len)?];
+        let forced = r.bits(len)?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        self.cur_out = cur_out;
+        self.state = state;
+        self.forced = forced;
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
+    fn show(&self) {
+        let pad = self.netlist.name_pad();
+
+        self.clock.show_ruler(pad);
+
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
+            println!("{name:pad$}{out}", name=name, pad=pad, out=out);
+        }
+
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
+    }
+
+    fn num_gates(&self) -> usize {
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        (self.state[self.cur_out][index] & 1) as u8
+    }
+
+    /// Forces the gate in every lane
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        self.forced.set(index, value.is_some() as u8);
+
+        if let Some(val) = value {
+            let b = if val != 0 { !0 } else { 0 };
+            self.state[0][index] = b;
+            self.state[1][index] = b;
+        }
+    }
+}
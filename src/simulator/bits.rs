@@ -0,0 +1,74 @@
+/// Per-gate flags packed one bit per gate into 64-bit words
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Bits {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bits {
+    pub fn new(len: usize) -> Self {
+        Bits {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> u8 {
+        ((self.words[index / 64] >> (index % 64)) & 1) as u8
+    }
+
+    #[inline]
+    pub fn set(&mut self, index: usize, val: u8) {
+        let word = &mut self.words[index / 64];
+        *word = (*word & !(1 << (index % 64))) | ((val as u64 & 1) << (index % 64));
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Bits past `len` in the last word must be left at zero
+    pub fn words_mut(&mut self) -> &mut [u64] {
+        &mut self.words
+    }
+}
+
+/// Mask of the bits of word `word` that belong to indices in `start..end`
+pub fn word_mask(word: usize, start: usize, end: usize) -> u64 {
+    let lo = start.saturating_sub(word * 64).min(64);
+    let hi = end.saturating_sub(word * 64).min(64);
+
+    let below = |n: usize| if n == 64 { !0 } else { (1u64 << n) - 1 };
+
+    below(hi) & !below(lo)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        let mut bits = Bits::new(130);
+        assert_eq!(bits.words().len(), 3);
+
+        bits.set(0, 1);
+        bits.set(64, 1);
+        bits.set(129, 1);
+        bits.set(129, 0);
+        bits.set(127, 1);
+
+        assert_eq!(bits.get(0), 1);
+        assert_eq!(bits.get(1), 0);
+        assert_eq!(bits.get(64), 1);
+        assert_eq!(bits.get(127), 1);
+        assert_eq!(bits.get(129), 0);
+        assert_eq!(bits.words(), &[1, 1 | (1 << 63), 0]);
+
+        assert_eq!(word_mask(0, 3, 130), !0 << 3);
+        assert_eq!(word_mask(1, 3, 130), !0);
+        assert_eq!(word_mask(2, 3, 130), 0b11);
+        assert_eq!(word_mask(2, 0, 64), 0);
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::simulator::{Gate, GateMeta, Input, Output, Simulator};
+use crate::simulator::{Gate, GateMeta, Input, OptimizerConfig, Output, Simulator};
+use crate::simulator::bus::{data_name, enable_name, read_name, BusMonitor};
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct V(u32);
@@ -14,11 +15,33 @@ impl From<bool> for V {
 #[derive(Copy, Clone, Debug, Default)]
 pub struct VVec(u32);
 
+#[derive(Copy, Clone, Debug)]
+pub struct Bus(u32);
+
 #[derive(Default)]
 pub struct GateBuilder {
     vecs: BTreeMap<u32, Vec<V>>,
     values: Vec<Value>,
     gates: Vec<Gate>,
+    buses: Vec<BusDef>,
+    optimizer: OptimizerConfig,
+    /// Value of the gate made by `nand` for each pair of resolved inputs
+    structure: HashMap<(u32, u32), u32>,
+    /// Inputs of each value that `nand` folded into another one, in case it needs a gate of its own
+    folded: HashMap<u32, (V, V)>,
+    /// Values in `folded` by the gate they stand for, and by the gates they read. Entries go stale
+    /// once a value gets a gate of its own, so they are checked again when used.
+    aliases: HashMap<u32, Vec<u32>>,
+    readers: HashMap<u32, Vec<u32>>,
+}
+
+struct BusDef {
+    value: VVec,
+    /// Inverted bus value, connected when the circuit is built
+    inverted: Vec<V>,
+    drivers: Vec<(String, VVec, V)>,
+    readers: Vec<(String, V)>,
+    monitor: BusMonitor,
 }
 
 enum Value {
@@ -29,35 +52,57 @@ enum Value {
 }
 
 impl GateBuilder {
+    /// Sets which optimizations `nand` and `build_simulator` apply to the circuit
+    pub fn optimizer(mut self, config: OptimizerConfig) -> Self {
+        self.optimizer = config;
+        self
+    }
+
     pub fn build_simulator<S: Simulator, R>(self, f: impl FnOnce() -> R) -> (R, S) {
-        let mut builder = GateBuilder::default();
+        let optimizer = self.optimizer;
+        let (r, gates) = self.build_gates(f);
+
+        (r, S::with_optimizer(gates.as_slice(), &optimizer))
+    }
+
+    /// Builds the circuit into a list of gates with all references resolved, without optimizing
+    /// it. This is what `build_simulator` hands to `Simulator::new`.
+    pub fn build_gates<R>(self, f: impl FnOnce() -> R) -> (R, Vec<Gate>) {
+        let mut builder = GateBuilder {
+            optimizer: self.optimizer,
+            ..Default::default()
+        };
 
         // reserve constant 0
         builder.input(1);
 
-        let (r, builder) = super::v::with_builder(builder, f);
+        let (r, mut builder) = super::v::with_builder(builder, f);
+
+        builder.finish_buses();
 
         (
             r,
-            S::new(
-                builder.gates
-                    .iter()
-                    .map(|g| Gate {
-                        id: g.id,
-                        a: builder.resolve_ref(g.a),
-                        b: builder.resolve_ref(g.b),
-                        meta: g.meta.clone(),
-                    })
-                    .collect::<Vec<_>>()
-                    .as_slice())
+            builder.gates
+                .iter()
+                .map(|g| Gate {
+                    id: g.id,
+                    a: builder.resolve_ref(g.a),
+                    b: builder.resolve_ref(g.b),
+                    meta: g.meta.clone(),
+                })
+                .collect()
         )
     }
 
     fn resolve_ref(&self, id: u32) -> u32 {
+        self.try_resolve_ref(id).expect("uninitialized V")
+    }
+
+    fn try_resolve_ref(&self, id: u32) -> Option<u32> {
         match self.values[id as usize] {
-            Value::Uninit => panic!("uninitialized V"),
-            Value::Ref(id) => self.resolve_ref(id),
-            Value::Gate(id) => id,
+            Value::Uninit => None,
+            Value::Ref(id) => self.try_resolve_ref(id),
+            Value::Gate(id) => Some(id),
         }
     }
 
@@ -96,8 +141,136 @@ impl GateBuilder {
         self.vecs.get(&vv.0).unwrap().len()
     }
 
+    /// Makes a NAND gate, or reuses an existing gate with the same output. Constant and doubly
+    /// negated inputs are folded the same way as by the optimizer. Inputs that aren't initialized
+    /// yet always get a new gate and are left for the optimizer.
     pub fn nand(&mut self, a: V, b: V) -> V {
-        self.make_gate(a, b).1
+        let (Some(ga), Some(gb)) = (self.try_resolve_ref(a.0), self.try_resolve_ref(b.0)) else {
+            return self.make_gate(a, b).1;
+        };
+
+        if let Some(v) = self.fold(a, b, ga, gb) {
+            return self.alias(v, a, b);
+        }
+
+        let key = (ga.min(gb), ga.max(gb));
+
+        if !self.optimizer.merge_duplicates {
+            return self.make_gate(a, b).1;
+        }
+
+        if let Some(&vid) = self.structure.get(&key) {
+            return self.alias(V(vid), a, b);
+        }
+
+        let (_, v) = self.make_gate(a, b);
+        self.structure.insert(key, v.0);
+        v
+    }
+
+    /// Finds a simpler value equal to nand(a, b) of the resolved gates `ga` and `gb`
+    fn fold(&mut self, a: V, b: V, ga: u32, gb: u32) -> Option<V> {
+        if self.optimizer.fold_constants {
+            if (ga == 0 || gb == 0) && (ga, gb) != (0, 0) {
+                // nand(a, 0), nand(0, b) -> nand(0, 0)
+                return Some(self.nand(Self::zero(), Self::zero()));
+            }
+
+            // nand(a, 1) -> nand(a, a) and nand(1, b) -> nand(b, b)
+            let b2 = if self.is_one(gb) { a } else { b };
+            let a2 = if self.is_one(ga) { b2 } else { a };
+
+            if (self.resolve_ref(a2.0), self.resolve_ref(b2.0)) != (ga, gb) {
+                return Some(self.nand(a2, b2));
+            }
+        }
+
+        if self.optimizer.remove_double_negations && ga == gb {
+            let inner = &self.gates[ga as usize];
+
+            // !!a -> a
+            if !inner.is_io() && !inner.is_pinned() && inner.delay() == 1
+                && self.try_resolve_ref(inner.a).is_some()
+                && self.try_resolve_ref(inner.a) == self.try_resolve_ref(inner.b)
+            {
+                return Some(V(inner.a));
+            }
+        }
+
+        None
+    }
+
+    /// A plain gate that reads constant 0, which makes its output constant 1
+    fn is_one(&self, gid: u32) -> bool {
+        let g = &self.gates[gid as usize];
+
+        !g.is_io() && !g.is_pinned() && g.delay() == 1
+            && self.try_resolve_ref(g.a) == Some(0)
+            && self.try_resolve_ref(g.b) == Some(0)
+    }
+
+    /// Makes a new value that refers to `v`, and remembers that it stands for nand(a, b)
+    fn alias(&mut self, v: V, a: V, b: V) -> V {
+        let vid = self.values.len() as u32;
+        self.values.push(Value::Ref(v.0));
+        self.folded.insert(vid, (a, b));
+
+        self.aliases.entry(self.resolve_ref(vid)).or_default().push(vid);
+
+        let (ga, gb) = (self.resolve_ref(a.0), self.resolve_ref(b.0));
+        self.readers.entry(ga).or_default().push(vid);
+
+        if gb != ga {
+            self.readers.entry(gb).or_default().push(vid);
+        }
+
+        V(vid)
+    }
+
+    /// Whether a value still in `folded` stands for the gate
+    fn is_alias(&self, vid: u32, gid: u32) -> bool {
+        self.folded.contains_key(&vid) && self.try_resolve_ref(vid) == Some(gid)
+    }
+
+    /// Whether a value still in `folded` reads the gate
+    fn is_reader(&self, vid: u32, gid: u32) -> bool {
+        self.folded
+            .get(&vid)
+            .is_some_and(|(a, b)| [a, b].iter().any(|v| self.try_resolve_ref(v.0) == Some(gid)))
+    }
+
+    /// Gives a value folded by `nand` a gate of its own, for when the gate is about to be named,
+    /// pinned, delayed or made an output. Returns the gate ID.
+    fn own_gate(&mut self, v: V) -> u32 {
+        if let Some((a, b)) = self.folded.remove(&v.0) {
+            let old = self.resolve_ref(v.0);
+            let gid = self.gates.len() as u32;
+
+            self.values[v.0 as usize] = Value::Gate(gid);
+            self.gates.push(Gate {
+                id: gid,
+                a: a.0,
+                b: b.0,
+                meta: None,
+            });
+
+            // values that referred to the old gate through this one now refer to the new gate
+            let aliases: Vec<u32> = self.aliases.get(&old).into_iter().flatten().copied().collect();
+            let moved: Vec<u32> = aliases.into_iter().filter(|&vid| self.is_alias(vid, gid)).collect();
+
+            if !moved.is_empty() {
+                self.aliases.entry(gid).or_default().extend(moved);
+            }
+
+            let readers: Vec<u32> = self.readers.get(&old).into_iter().flatten().copied().collect();
+            let moved: Vec<u32> = readers.into_iter().filter(|&vid| self.is_reader(vid, gid)).collect();
+
+            if !moved.is_empty() {
+                self.readers.entry(gid).or_default().extend(moved);
+            }
+        }
+
+        self.resolve_ref(v.0)
     }
 
     pub fn input(&mut self, size: usize) -> (Input, VVec) {
@@ -132,7 +305,7 @@ impl GateBuilder {
             self.vv_get(vv)
                 .iter()
                 .map(|v| {
-                    let gid = self.resolve_ref(v.0);
+                    let gid = self.own_gate(*v);
                     let gate = &mut self.gates[gid as usize];
 
                     match gate.meta() {
@@ -162,12 +335,283 @@ impl GateBuilder {
     }
 
     pub fn name(&mut self, v: V, name: &str) {
-        let gid = self.resolve_ref(v.0);
-        self.gates[gid as usize].add_meta().name = Some(name.to_owned());
+        // the optimizer moves the name to the equivalent gate later, but keeping the gate until then
+        // keeps the name on this value if it's made an output or pinned afterwards
+        let gid = self.own_gate(v);
+        let names = &mut self.gates[gid as usize].add_meta().names;
+
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
     }
 
     pub fn pin(&mut self, v: V) {
-        let gid = self.resolve_ref(v.0);
+        let gid = self.own_gate(v);
         self.gates[gid as usize].add_meta().pinned = true;
+        self.unfold_readers(gid);
+    }
+
+    pub fn bus(&mut self, name: &str, bits: usize) -> Bus {
+        let id = self.buses.len() as u32;
+
+        // the value is a gate from the start, so that it can be named and made an output before
+        // all drivers are attached
+        let inverted: Vec<V> = (0..bits).map(|_| self.v()).collect();
+        let value = inverted.iter().map(|&n| self.nand(n, n)).collect();
+        let value = self.vv_from(value);
+
+        for (bit, v) in self.vv_get(value).into_iter().enumerate() {
+            self.name(v, &format!("{} {}", name, bit));
+        }
+
+        self.buses.push(BusDef {
+            value,
+            inverted,
+            drivers: Vec::new(),
+            readers: Vec::new(),
+            monitor: BusMonitor {
+                name: name.to_owned(),
+                drivers: Vec::new(),
+                readers: Vec::new(),
+            },
+        });
+
+        Bus(id)
+    }
+
+    pub fn bus_value(&mut self, bus: Bus) -> VVec {
+        self.buses[bus.0 as usize].value
+    }
+
+    pub fn bus_drive(&mut self, bus: Bus, name: &str, data: VVec, enable: V) {
+        if self.vv_len(data) != self.vv_len(self.buses[bus.0 as usize].value) {
+            panic!("V len mismatch");
+        }
+
+        let def = &mut self.buses[bus.0 as usize];
+        def.drivers.push((name.to_owned(), data, enable));
+        def.monitor.drivers.push(name.to_owned());
+    }
+
+    pub fn bus_read(&mut self, bus: Bus, name: &str, enable: V) {
+        let def = &mut self.buses[bus.0 as usize];
+        def.readers.push((name.to_owned(), enable));
+        def.monitor.readers.push(name.to_owned());
+    }
+
+    pub fn bus_monitor(&mut self, bus: Bus) -> BusMonitor {
+        self.buses[bus.0 as usize].monitor.clone()
+    }
+
+    /// Connects each bus to the OR of its enabled drivers, and names the driver and reader signals
+    /// for its `BusMonitor`
+    fn finish_buses(&mut self) {
+        for bus in std::mem::take(&mut self.buses) {
+            let name = &bus.monitor.name;
+
+            for (driver, data, enable) in &bus.drivers {
+                self.name(*enable, &enable_name(name, driver));
+
+                for (bit, d) in self.vv_get(*data).into_iter().enumerate() {
+                    self.name(d, &format!("{} {}", data_name(name, driver), bit));
+                }
+            }
+
+            for (reader, enable) in &bus.readers {
+                self.name(*enable, &read_name(name, reader));
+            }
+
+            for (bit, inverted) in bus.inverted.into_iter().enumerate() {
+                // the inverted value is the AND of all nand(data, enable)
+
+                let mut all = None;
+
+                for &(_, data, enable) in &bus.drivers {
+                    let d = self.vv_get(data)[bit];
+                    let n = self.nand(d, enable);
+
+                    all = Some(match all {
+                        None => n,
+                        Some(all) => {
+                            let a = self.nand(all, n);
+                            self.nand(a, a)
+                        },
+                    });
+                }
+
+                let all = match all {
+                    Some(all) => all,
+                    None => self.nand(Self::zero(), Self::zero()),
+                };
+
+                self.set(inverted, all);
+            }
+        }
+    }
+
+    pub fn delay(&mut self, v: V, delay: u32) {
+        assert!(delay >= 1, "gate delay must be at least 1");
+
+        let gid = self.own_gate(v);
+        self.gates[gid as usize].add_meta().delay = Some(delay);
+
+        // a delayed gate is no longer the same as others with the same inputs
+        // owning one can move others that refer to the gate through it, so the entry goes last
+        for vid in self.aliases.get(&gid).cloned().unwrap_or_default() {
+            if self.is_alias(vid, gid) {
+                self.own_gate(V(vid));
+            }
+        }
+
+        self.aliases.remove(&gid);
+
+        self.unfold_readers(gid);
+
+        let g = &self.gates[gid as usize];
+        if let (Some(ga), Some(gb)) = (self.try_resolve_ref(g.a), self.try_resolve_ref(g.b)) {
+            let key = (ga.min(gb), ga.max(gb));
+
+            if self.structure.get(&key).map(|&vid| self.resolve_ref(vid)) == Some(gid) {
+                self.structure.remove(&key);
+            }
+        }
+    }
+
+    /// Gives every value folded by `nand` that reads the gate a gate of its own, since folding `!!a`
+    /// or a constant 1 input only holds while the gate is a plain one with the default delay
+    fn unfold_readers(&mut self, gid: u32) {
+        for vid in self.readers.get(&gid).cloned().unwrap_or_default() {
+            if self.is_reader(vid, gid) {
+                self.own_gate(V(vid));
+            }
+        }
+
+        self.readers.remove(&gid);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+    use super::GateBuilder;
+
+    /// Gates that the optimizer would still merge or fold after the builder
+    fn redundant(gates: &[Gate]) -> usize {
+        let report = crate::simulator::optimizer::optimize_gates(&mut gates.to_vec(), &OptimizerConfig::default());
+        report.constants_folded + report.duplicates_merged + report.double_negations_removed
+    }
+
+    #[test]
+    fn test_nand_folding() {
+        let circuit = || {
+            let (_, a) = input(1);
+            let (_, b) = input(1);
+            let (a, b) = (a.at(0), b.at(0));
+
+            nand(a, b);
+            nand(b, a);
+            nand(a, zero());
+            one();
+            nand(one(), b);
+            let _ = !b;
+            let _ = !!a;
+
+            // not known yet, so both are left for the optimizer
+            let c = v();
+            let _ = !c;
+            let _ = !c;
+            c << a;
+        };
+
+        let (_, gates) = build_gates(circuit);
+        let (_, unfolded) = GateBuilder::default().optimizer(OptimizerConfig::none()).build_gates(circuit);
+
+        // constant 0, a, b, nand(a, b), one, !b, !a and both !c
+        assert_eq!(gates.len(), 9);
+        assert_eq!(unfolded.len(), 14);
+    }
+
+    #[test]
+    fn test_nand_unfolding() {
+        let (outputs, gates) = build_gates(|| {
+            let (_, a) = input(1);
+            let (_, b) = input(1);
+            let (a, b) = (a.at(0), b.at(0));
+
+            // folded to a and to !a, until the inner gates stop being plain ones
+            let n = !a;
+            let x = !n;
+            n.delay(3);
+
+            let o = one();
+            let y = nand(a, o);
+            o.pin();
+
+            (nand(x, b).output(), nand(y, b).output())
+        });
+
+        // the gates that the gates read by each output read
+        let reads = |output: &Output| {
+            let g = &gates[output.0[0] as usize];
+            [g.a, g.b]
+                .into_iter()
+                .flat_map(|id| [gates[id as usize].a, gates[id as usize].b])
+                .map(|id| &gates[id as usize])
+                .collect::<Vec<_>>()
+        };
+
+        assert!(reads(&outputs.0).iter().any(|g| g.delay() == 3));
+        assert!(reads(&outputs.1).iter().any(|g| g.is_pinned()));
+    }
+
+    #[test]
+    fn test_nand_pinned_not_folded() {
+        let (x, gates) = build_gates(|| {
+            let (_, a) = input(1);
+            let (_, b) = input(1);
+            let n = !a.at(0);
+            n.pin();
+            nand(!n, b.at(0)).output()
+        });
+
+        // the output reads !n, which reads the pinned n rather than being folded to a
+        let g = &gates[x.0[0] as usize];
+        let reads = |id: u32| [gates[id as usize].a, gates[id as usize].b];
+        assert!([g.a, g.b].into_iter().flat_map(reads).any(|id| gates[id as usize].is_pinned()));
+    }
+
+    #[test]
+    fn test_nand_unfolding_through_alias() {
+        let (x, gates) = build_gates(|| {
+            let (_, a) = input(1);
+            let (_, b) = input(1);
+            let (a, b) = (a.at(0), b.at(0));
+
+            // y is folded into nand(a, b), and z into y, until y is named and then delayed
+            nand(a, b);
+            let y = nand(a, b);
+            let z = !!y;
+            y.name("y");
+            y.delay(3);
+
+            nand(z, b).output()
+        });
+
+        let g = &gates[x.0[0] as usize];
+        assert!([g.a, g.b].iter().all(|&id| gates[id as usize].delay() == 1));
+    }
+
+    #[test]
+    fn test_decoder_rom_not_redundant() {
+        let (_, gates) = build_gates(|| {
+            let (_, addr) = input(4);
+            let (_, sel) = input(1);
+
+            decoder(addr).output();
+            rom(8, &[0x12, 0x10, 0x13, 0x01, 0x28, 0x11, 0xf0, 0x34], addr.slice(0..3), sel.at(0)).output();
+        });
+
+        assert_eq!(redundant(&gates), 0);
     }
 }
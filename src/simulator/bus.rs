@@ -0,0 +1,231 @@
+use std::fmt;
+
+use crate::simulator::{Signal, Simulator, WatchError};
+use crate::simulator::netlist::Netlist;
+
+/// Observes the drivers and readers of a tri-state bus made with `bus()` in a running simulator.
+///
+/// The enable and data of each driver and the enable of each reader are named after the bus, such
+/// as "data.cpu oe", "data.cpu 0" and "data.alu rd", and read from the simulator by name.
+#[derive(Clone)]
+pub struct BusMonitor {
+    pub(super) name: String,
+    pub(super) drivers: Vec<String>,
+    pub(super) readers: Vec<String>,
+}
+
+/// Name of the data bits of a bus driver, without the bit number
+pub(super) fn data_name(bus: &str, driver: &str) -> String {
+    format!("{}.{}", bus, driver)
+}
+
+pub(super) fn enable_name(bus: &str, driver: &str) -> String {
+    format!("{}.{} oe", bus, driver)
+}
+
+pub(super) fn read_name(bus: &str, reader: &str) -> String {
+    format!("{}.{} rd", bus, reader)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BusFault {
+    /// More than one driver is enabled and they do not agree. Lists each enabled driver and the
+    /// value it drives.
+    Contention { bus: String, drivers: Vec<(String, u64)> },
+
+    /// No driver is enabled while the bus is read. Lists the enabled readers, or is empty if the
+    /// bus has no registered readers.
+    Floating { bus: String, readers: Vec<String> },
+}
+
+impl fmt::Display for BusFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusFault::Contention { bus, drivers } => {
+                write!(f, "contention on {}:", bus)?;
+                for (name, value) in drivers {
+                    write!(f, " {}={:#x}", name, value)?;
+                }
+                Ok(())
+            },
+            BusFault::Floating { bus, readers } if readers.is_empty() => {
+                write!(f, "{} is floating", bus)
+            },
+            BusFault::Floating { bus, readers } => {
+                write!(f, "{} is floating while read by {}", bus, readers.join(", "))
+            },
+        }
+    }
+}
+
+impl BusMonitor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks the current state of the bus in a simulator built from the same circuit
+    pub fn check<S: Simulator>(&self, sim: &S) -> Option<BusFault> {
+        self.signals(sim.netlist())
+            .expect("bus monitor from a different circuit")
+            .check(|index| sim.gate_value(index))
+    }
+
+    /// Looks up the driver and reader signals in a netlist
+    pub(super) fn signals(&self, netlist: &Netlist) -> Result<BusSignals, WatchError> {
+        Ok(BusSignals {
+            name: self.name.clone(),
+            drivers: self.drivers
+                .iter()
+                .map(|d| Ok((
+                    d.clone(),
+                    Signal::net(netlist, &enable_name(&self.name, d))?,
+                    Signal::bus(netlist, &data_name(&self.name, d))?,
+                )))
+                .collect::<Result<_, WatchError>>()?,
+            readers: self.readers
+                .iter()
+                .map(|r| Ok((r.clone(), Signal::net(netlist, &read_name(&self.name, r))?)))
+                .collect::<Result<_, WatchError>>()?,
+        })
+    }
+}
+
+/// The signals of a bus, as found by `BusMonitor::signals`
+pub(super) struct BusSignals {
+    name: String,
+    /// Name, enable and data of each driver
+    drivers: Vec<(String, Signal, Signal)>,
+    readers: Vec<(String, Signal)>,
+}
+
+impl BusSignals {
+    pub(super) fn check(&self, gate_value: impl Fn(usize) -> u8) -> Option<BusFault> {
+        let enabled: Vec<(String, u64)> = self.drivers
+            .iter()
+            .filter(|(_, enable, _)| enable.read(&gate_value) != 0)
+            .map(|(name, _, data)| (name.clone(), data.read(&gate_value)))
+            .collect();
+
+        if enabled.iter().any(|(_, value)| *value != enabled[0].1) {
+            return Some(BusFault::Contention {
+                bus: self.name.clone(),
+                drivers: enabled,
+            });
+        }
+
+        if enabled.is_empty() {
+            let readers: Vec<String> = self.readers
+                .iter()
+                .filter(|(_, enable)| enable.read(&gate_value) != 0)
+                .map(|(name, _)| name.clone())
+                .collect();
+
+            if self.readers.is_empty() || !readers.is_empty() {
+                return Some(BusFault::Floating {
+                    bus: self.name.clone(),
+                    readers,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_bus() {
+        let ((a_i, a_oe_i, b_i, b_oe_i, rd_i, value, monitor), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(4);
+            let (a_oe_i, a_oe) = input(1);
+            let (b_i, b) = input(4);
+            let (b_oe_i, b_oe) = input(1);
+            let (rd_i, rd) = input(1);
+
+            let data = bus("data", 4)
+                .drive("a", a, a_oe.at(0))
+                .drive("b", b, b_oe.at(0))
+                .read("reader", rd.at(0));
+
+            (a_i, a_oe_i, b_i, b_oe_i, rd_i, data.value().output(), data.monitor())
+        });
+
+        sim.set(&a_i, 0x5u8);
+        sim.set(&b_i, 0xau8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(monitor.check(&sim), None);
+
+        sim.set(&rd_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(monitor.check(&sim), Some(BusFault::Floating {
+            bus: "data".into(),
+            readers: vec!["reader".into()],
+        }));
+
+        sim.set(&a_oe_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(monitor.check(&sim), None);
+        assert_eq!(sim.get::<u8>(&value), 0x5);
+
+        sim.set(&b_oe_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        let fault = monitor.check(&sim).unwrap();
+        assert_eq!(fault.to_string(), "contention on data: a=0x5 b=0xa");
+
+        sim.set(&b_i, 0x5u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(monitor.check(&sim), None);
+    }
+
+    #[test]
+    fn test_watch_bus() {
+        let ((clk_i, rst_i, a_i, b_i, c_i, monitor), sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (a_i, a) = input(4);
+            let (b_i, b) = input(4);
+            let (c_i, c) = input(4);
+
+            let q = vv(2);
+            let m = latch(increment(q), one(), clk.at(0), !rst.at(0));
+            q << latch(m, one(), !clk.at(0), !rst.at(0));
+            q.name("q");
+
+            let data = bus("data", 4)
+                .drive("even", a, !q.at(0))
+                .drive("odd", b, q.at(0))
+                .drive("three", c, q.at(0) & q.at(1));
+
+            data.value().output();
+            (clk_i, rst_i, a_i, b_i, c_i, data.monitor())
+        });
+
+        let mut sim = Watched::new(sim);
+        sim.set(&a_i, 0x5u8);
+        sim.set(&b_i, 0xau8);
+        sim.set(&c_i, 0x3u8);
+        sim.set_clock(&clk_i);
+        sim.set(&rst_i, 1u8);
+        sim.settle(100).unwrap();
+        sim.set(&rst_i, 0u8);
+        sim.settle(100).unwrap();
+
+        let id = sim.watch_bus(&monitor).unwrap();
+
+        // the drivers take turns without the overlap while switching being reported
+        let fault = BusFault::Contention {
+            bus: "data".into(),
+            drivers: vec![("odd".into(), 0xa), ("three".into(), 0x3)],
+        };
+        assert!(matches!(sim.run_cycles(10), Err(Stop::Bus { id: i, fault: f, .. }) if i == id && f == fault));
+        assert_eq!(sim.probe_bus("q"), Ok(3));
+
+        sim.set(&c_i, 0xau8);
+        assert!(sim.settle(100).is_ok());
+        sim.run_cycles(10).unwrap();
+    }
+}
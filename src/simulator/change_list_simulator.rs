@@ -1,97 +1,296 @@
-use std::mem::swap;
-use std::collections::BTreeMap;
+use std::io;
+use std::mem::{swap, take};
 
 use rayon::prelude::*;
 
 use crate::simulator::*;
+use crate::simulator::netlist::{Fanout, Netlist};
+use crate::simulator::bits::Bits;
+use crate::simulator::oscillation::find_oscillations;
+use crate::simulator::state_file::{StateReader, StateWriter};
 
+/// Default number of gates pending in a step below which partitioned mode runs the step on the
+/// calling thread. Handing the change list out to the workers costs tens of microseconds per step,
+/// about as much as evaluating 10k gates.
+const PARALLEL_MIN_PENDING: usize = 16384;
+
+/// Simulator that only evaluates gates with an input that changed in the previous step.
+///
+/// By default the change list is evaluated in place, so a gate can see values changed earlier in the
+/// same step. `set_two_phase` and `set_partitions` select evaluation where, like in
+/// `SimpleSimulator`, every gate in a step reads the state from the end of the previous step.
 pub struct ChangeListSimulator {
     state: Vec<u8>,
+    /// Gates held at their current value by `force`
+    forced: Bits,
+    two_phase: bool,
+    /// Gates that flip at the end of the step in two-phase mode
+    changes: Vec<u32>,
+    /// Gates in the change list for the next step in two-phase mode, so that each is listed once and
+    /// can't flip twice. Cleared as the change list is evaluated. Unused in the in-place mode, where a
+    /// gate listed twice just finds nothing to change the second time.
+    scheduled: Bits,
     change_list: Vec<u32>,
     new_change_list: Vec<u32>,
-    names: Vec<(usize, String, String)>,
-    input_map: BTreeMap<u32, usize>,
-    output_map: BTreeMap<u32, usize>,
-    n_inputs: usize,
-    gates: Vec<(u32, u32, Vec<u32>)>,
+    partitions: Vec<Partition>,
+    partition_size: usize,
+    parallel_min_pending: usize,
+    netlist: Netlist,
+    traces: Vec<String>,
+    clock: Clock,
+    fanout: Fanout,
 }
 
-impl Simulator for ChangeListSimulator {
-    fn new(gates: &[Gate]) -> Self {
-        let mut gates = gates.to_vec();
+/// A contiguous range of gates evaluated by one worker in partitioned mode
+#[derive(Default)]
+struct Partition {
+    change_list: Vec<u32>,
+    /// Gates whose value flips at the end of this step
+    changes: Vec<u32>,
+}
+
+impl ChangeListSimulator {
+    /// Runs the simulation for a number of steps while counting how often each gate toggles, and
+    /// reports the feedback loops that kept toggling along with the named signals nearest to them.
+    /// Use this to find out why `step_until_settled` returned None.
+    pub fn diagnose_oscillation(&mut self, steps: usize) -> Vec<Oscillation> {
+        let mut toggles = vec![0u32; self.netlist.len()];
+        let mut prev = self.state.clone();
+
+        for _ in 0..steps {
+            self.step();
+
+            for ((toggles, &cur), prev) in toggles.iter_mut().zip(&self.state).zip(&mut prev) {
+                *toggles += (cur != *prev) as u32;
+                *prev = cur;
+            }
+        }
+
+        find_oscillations(&self.netlist, &toggles)
+    }
+
+    /// Splits the gates into a number of partitions that are evaluated in parallel, or returns to
+    /// the single-threaded mode if `partitions` is 0 or 1.
+    ///
+    /// Partitioned mode is always two-phase, so it turns on `set_two_phase(true)`. It then gives
+    /// exactly the same results as the single-threaded two-phase mode for any number of partitions,
+    /// which stays on when returning to a single thread. Steps with few gates to evaluate run on the
+    /// calling thread.
+    pub fn set_partitions(&mut self, partitions: usize) {
+        self.partitions.clear();
 
-        super::optimizer::optimize_gates(&mut gates);
+        if partitions <= 1 {
+            return;
+        }
+
+        self.set_two_phase(true);
+
+        let size = self.netlist.len().div_ceil(partitions);
+        let count = self.netlist.len().div_ceil(size);
+
+        self.partition_size = size;
+        self.partitions = (0..count).map(|_| Partition::default()).collect();
+
+        // with a single worker thread the parallel path is all overhead
+        self.parallel_min_pending = match rayon::current_num_threads() {
+            1 => usize::MAX,
+            _ => PARALLEL_MIN_PENDING,
+        };
+    }
+
+    /// Selects between the default in-place mode and the two-phase mode, which computes the new value
+    /// of every gate in a step before committing any of them so that like in `SimpleSimulator`, each
+    /// gate reads the state from the end of the previous step.
+    ///
+    /// The in-place mode lets a gate see values changed earlier in the same step, which saves a pass
+    /// over the changes but makes race-sensitive circuits such as `rising_edge` depend on evaluation
+    /// order. Ignored while partitioned, which is always two-phase.
+    pub fn set_two_phase(&mut self, enable: bool) {
+        if !self.partitions.is_empty() {
+            return;
+        }
+
+        let pending = take(&mut self.change_list);
+
+        for &index in &pending {
+            self.scheduled.set(index as usize, 0);
+        }
+
+        self.two_phase = enable;
+        self.set_change_list(pending);
+    }
 
-        gates.sort_by_key(|g| (
-            std::cmp::Reverse(g.is_input()),
-            g.id,
-        ));
+    /// Replaces the change list for the next step
+    fn set_change_list(&mut self, pending: Vec<u32>) {
+        for &index in &self.change_list {
+            self.scheduled.set(index as usize, 0);
+        }
+
+        self.change_list.clear();
+
+        for index in pending {
+            self.schedule_gate(index);
+        }
+    }
+
+    /// Schedules a gate for evaluation in the next step, unless it already is in two-phase mode
+    fn schedule_gate(&mut self, index: u32) {
+        if !self.two_phase {
+            self.change_list.push(index);
+        } else if self.scheduled.get(index as usize) == 0 {
+            self.scheduled.set(index as usize, 1);
+            self.change_list.push(index);
+        }
+    }
+
+    /// Schedules the readers of a gate for evaluation in the next step
+    fn schedule(&mut self, index: usize) {
+        for i in 0..self.fanout[index].len() {
+            self.schedule_gate(self.fanout[index][i]);
+        }
+    }
+
+    fn is_settled(&self) -> bool {
+        self.change_list.is_empty()
+    }
+
+    fn step_two_phase(&mut self) {
+        self.changes.clear();
+
+        // each gate is listed once, so none can flip twice
+        for index in self.change_list.iter().copied() {
+            let g = &self.netlist.gates[index as usize];
+
+            let val = (self.state[g.0 as usize] & self.state[g.1 as usize]) ^ 0x01;
+
+            if val != self.state[index as usize] && self.forced.get(index as usize) == 0 {
+                self.changes.push(index);
+            }
+        }
+
+        for index in self.changes.iter().copied() {
+            self.state[index as usize] ^= 1;
+        }
+
+        self.schedule_changes();
+    }
 
-        let index_map: BTreeMap<_, _> = gates
-            .iter()
+    /// Evaluates the change list in parallel, with each partition evaluating and committing its own
+    /// gates, unless it is too short to be worth it
+    fn step_partitioned(&mut self) {
+        if self.change_list.len() < self.parallel_min_pending {
+            self.step_two_phase();
+            return;
+        }
+
+        let size = self.partition_size;
+
+        for index in self.change_list.iter().copied() {
+            self.partitions[index as usize / size].change_list.push(index);
+        }
+
+        let state = &self.state;
+        let forced = &self.forced;
+        let gates = &self.netlist.gates;
+
+        self.partitions
+            .par_iter_mut()
+            .for_each(|p| {
+                p.changes.clear();
+
+                for &index in &p.change_list {
+                    let g = &gates[index as usize];
+
+                    let val = (state[g.0 as usize] & state[g.1 as usize]) ^ 0x01;
+
+                    if val != state[index as usize] && forced.get(index as usize) == 0 {
+                        p.changes.push(index);
+                    }
+                }
+
+                p.change_list.clear();
+            });
+
+        // each partition commits to its own slice of the state
+        self.state
+            .par_chunks_mut(size)
+            .zip(self.partitions.par_iter())
             .enumerate()
-            .map(|(index, g)| (g.id, index as u32))
-            .collect();
-
-        let n_inputs = gates
-            .iter()
-            .take_while(|g| g.is_input())
-            .count();
-
-        ChangeListSimulator {
-            state: vec![0; gates.len()],
-            change_list: gates
-                .iter()
-                .filter(|g| !g.is_input())
-                .map(|g| *index_map.get(&g.id).unwrap())
-                .collect(),
-            new_change_list: vec![],
-            names: gates
-                .iter()
-                .filter_map(|g|
-                    g.meta()
-                        .and_then(|m| m.name.as_ref())
-                        .map(|n| (g.id, n.clone())))
-                .map(|(id, name)| (*index_map.get(&id).unwrap() as usize, name, String::new()))
-                .collect(),
-            input_map: gates
-                .iter()
-                .filter_map(|g|
-                    g.meta()
-                        .and_then(|m| m.input_id)
-                        .map(|ioid| (ioid, *index_map.get(&g.id).unwrap() as usize)))
-                .collect(),
-            output_map: gates
-                .iter()
-                .filter_map(|g|
-                        g.meta()
-                        .and_then(|m| m.output_id)
-                        .map(|ioid| (ioid, *index_map.get(&g.id).unwrap() as usize)))
-                .collect(),
-            n_inputs,
-            gates: gates
-                .iter()
-                .map(|g| (
-                    *index_map.get(&g.a).unwrap(),
-                    *index_map.get(&g.b).unwrap(),
-                    gates
-                        .iter()
-                        .filter(|g2| !g2.is_input() && (g2.a == g.id || g2.b == g.id))
-                        .map(|g2| *index_map.get(&g2.id).unwrap())
-                        .collect()
-                ))
-                .collect(),
+            .for_each(|(n, (state, p))| {
+                for &index in &p.changes {
+                    state[index as usize - n * size] ^= 1;
+                }
+            });
+
+        self.changes.clear();
+        for p in &self.partitions {
+            self.changes.extend_from_slice(&p.changes);
+        }
+
+        self.schedule_changes();
+    }
+
+    /// Makes the readers of the gates in `changes` the change list for the next step
+    fn schedule_changes(&mut self) {
+        self.new_change_list.clear();
+
+        for &index in &self.change_list {
+            self.scheduled.set(index as usize, 0);
+        }
+
+        for index in self.changes.iter().copied() {
+            for &f in &self.fanout[index as usize] {
+                if self.scheduled.get(f as usize) == 0 {
+                    self.scheduled.set(f as usize, 1);
+                    self.new_change_list.push(f);
+                }
+            }
         }
+
+        swap(&mut self.change_list, &mut self.new_change_list);
+    }
+}
+
+impl Simulator for ChangeListSimulator {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+
+        let change_list: Vec<u32> = (netlist.n_inputs as u32..netlist.len() as u32).collect();
+
+        let mut sim = ChangeListSimulator {
+            state: vec![0; netlist.len()],
+            forced: Bits::new(netlist.len()),
+            two_phase: false,
+            changes: vec![],
+            scheduled: Bits::new(netlist.len()),
+            change_list: vec![],
+            new_change_list: vec![],
+            partitions: vec![],
+            partition_size: 0,
+            parallel_min_pending: 0,
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            fanout: netlist.fanout(),
+            netlist,
+        };
+
+        sim.set_change_list(change_list);
+        sim
     }
 
     fn set(&mut self, input: &Input, bits: impl Into<u64>) {
         let bits = bits.into();
 
         for (bit, id) in input.0.iter().copied().enumerate() {
-            let index = *self.input_map.get(&id).unwrap();
+            let index = self.netlist.input_index(id);
             let b = bits & (1 << bit) != 0;
+
+            if self.forced.get(index) != 0 {
+                continue;
+            }
+
             self.state[index] = b as u8;
-            self.change_list.extend_from_slice(&self.gates[index].2);
+            self.schedule(index);
         }
     }
 
@@ -101,7 +300,7 @@ impl Simulator for ChangeListSimulator {
         let mut r = 0u64;
 
         for (bit, id) in output.0.iter().copied().enumerate() {
-            let index = *self.output_map.get(&id).unwrap();
+            let index = self.netlist.output_index(id);
             r |= (self.state[index] as u64) << bit;
         }
 
@@ -110,16 +309,26 @@ impl Simulator for ChangeListSimulator {
 
     /// Runs the simulation for one timestep
     fn step(&mut self) {
+        if !self.partitions.is_empty() {
+            self.step_partitioned();
+            return;
+        }
+
+        if self.two_phase {
+            self.step_two_phase();
+            return;
+        }
+
         self.new_change_list.clear();
 
         for index in self.change_list.iter().copied() {
-            let g = &self.gates[index as usize];
+            let g = &self.netlist.gates[index as usize];
 
             let val = (self.state[g.0 as usize] & self.state[g.1 as usize]) ^ 0x01;
 
-            if val != self.state[index as usize] {
+            if val != self.state[index as usize] && self.forced.get(index as usize) == 0 {
                 self.state[index as usize] = val;
-                self.new_change_list.extend_from_slice(&g.2);
+                self.new_change_list.extend_from_slice(&self.fanout[index as usize]);
             }
         }
 
@@ -137,7 +346,7 @@ impl Simulator for ChangeListSimulator {
 
             self.step();
 
-            if self.change_list.is_empty() {
+            if self.is_settled() {
                 return Some(i);
             }
         }
@@ -146,24 +355,419 @@ impl Simulator for ChangeListSimulator {
     }
 
     fn snapshot(&mut self) {
-        for (index, _, out) in &mut self.names {
-            let v = self.state[*index as usize] != 0;
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            let v = self.state[*index] != 0;
             out.push(if v { '█' } else { '▁' })
         }
     }
 
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        let mut w = StateWriter::new("ChangeListSimulator", self.netlist.fingerprint());
+        w.u8s(&self.state);
+        w.u32s(&self.change_list);
+        w.bits(&self.forced);
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = self.netlist.len();
+
+        let mut r = StateReader::new(bytes, "ChangeListSimulator", self.netlist.fingerprint())?;
+        let state = r.values(len, 1)?;
+        let pending = r.indices(self.netlist.n_inputs..len)?;
+        let forced = r.bits(len)?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        self.state = state;
+        self.forced = forced;
+        self.set_change_list(pending);
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
     fn show(&self) {
-        let pad = self.names.iter().map(|(_, name, _)| name.len()).max().unwrap() + 1;
+        let pad = self.netlist.name_pad();
 
-        for (_, name, out) in &self.names {
+        self.clock.show_ruler(pad);
+
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
             println!("{name:pad$}{out}", name=name, pad=pad, out=out);
         }
 
         //println!("max steps: {}", self.max_steps);
-        println!("gates: {}", self.gates.len());
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
     }
 
     fn num_gates(&self) -> usize {
-        self.gates.len()
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.state[index]
+    }
+
+    /// The change list of the last step, which was swapped out for the next one
+    fn changed_gates(&self) -> Option<&[u32]> {
+        Some(&self.new_change_list)
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        match value {
+            Some(val) => {
+                self.forced.set(index, 1);
+
+                if self.state[index] != val {
+                    self.state[index] = val;
+                    self.schedule(index);
+                }
+            }
+            None => {
+                self.forced.set(index, 0);
+
+                if index >= self.netlist.n_inputs {
+                    self.schedule_gate(index as u32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::{cpu_system, ram_system, CpuSystem};
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    fn run(sim: &mut ChangeListSimulator, io: &CpuSystem, half_clocks: usize) -> Vec<(u8, u8, u8)> {
+        (0..half_clocks)
+            .map(|t| {
+                sim.set(&io.clk, (t % 2) as u8);
+                sim.step_by(20);
+                (sim.get(&io.addr), sim.get(&io.data), sim.get(&io.w))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_partitioned_matches_single_threaded() {
+        let (io, gates) = build_gates(cpu_system);
+
+        let mut simple = SimpleSimulator::new(&gates);
+        let mut single = ChangeListSimulator::new(&gates);
+        single.set_two_phase(true);
+
+        // the CPU is too small for the parallel path to pay off, so the second one always takes it
+        let mut parallel = [ChangeListSimulator::new(&gates), ChangeListSimulator::new(&gates)];
+        parallel[0].set_partitions(7);
+        parallel[1].set_partitions(7);
+        parallel[1].parallel_min_pending = 0;
+        assert!(parallel[0].two_phase);
+
+        for t in 0..2000 {
+            let (rst, clk) = match t {
+                0..=99 => (1u8, 0u8),
+                _ => (0, ((t / 100) % 2) as u8),
+            };
+
+            simple.set(&io.rst, rst);
+            simple.set(&io.clk, clk);
+            simple.step();
+
+            for sim in std::iter::once(&mut single).chain(&mut parallel) {
+                sim.set(&io.rst, rst);
+                sim.set(&io.clk, clk);
+                sim.step();
+            }
+
+            for output in [&io.addr, &io.data, &io.w] {
+                let expected: u8 = simple.get(output);
+                assert_eq!(single.get::<u8>(output), expected);
+            }
+
+            for sim in &parallel {
+                assert_eq!(sim.state, single.state, "t = {}", t);
+            }
+        }
+    }
+
+    /// Times the single-threaded modes against partitioned mode on a large netlist. Run with
+    /// `cargo test --release bench_partitioned -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_partitioned() {
+        let clocks = 500;
+        let (io, gates) = build_gates(|| ram_system(10));
+
+        let run = |sim: &mut ChangeListSimulator| -> Vec<u8> {
+            sim.set(&io.rst, 1u8);
+            sim.step_until_settled(1000).unwrap();
+            sim.set(&io.rst, 0u8);
+            sim.set(&io.w, 1u8);
+
+            let start = std::time::Instant::now();
+
+            let q = (0..clocks)
+                .map(|t| {
+                    sim.set(&io.addr, (t * 7 % 1024) as u16);
+                    sim.set(&io.data, t as u8);
+                    sim.set(&io.clk, 0u8);
+                    sim.step_until_settled(1000).unwrap();
+                    sim.set(&io.clk, 1u8);
+                    sim.step_until_settled(1000).unwrap();
+                    sim.get(&io.q)
+                })
+                .collect();
+
+            let elapsed = start.elapsed();
+            println!("  {:.0} clocks/s", clocks as f64 / elapsed.as_secs_f64());
+            q
+        };
+
+        let mut sim = ChangeListSimulator::new(&gates);
+        println!("{} gates, {} threads", sim.num_gates(), rayon::current_num_threads());
+
+        println!("in place:");
+        let expected = run(&mut sim);
+
+        println!("two-phase:");
+        let mut sim = ChangeListSimulator::new(&gates);
+        sim.set_two_phase(true);
+        assert_eq!(run(&mut sim), expected);
+
+        for partitions in [2, 4, 8] {
+            println!("{} partitions:", partitions);
+            let mut sim = ChangeListSimulator::new(&gates);
+            sim.set_partitions(partitions);
+            assert_eq!(run(&mut sim), expected);
+
+            println!("{} partitions, always parallel:", partitions);
+            sim.parallel_min_pending = 0;
+            assert_eq!(run(&mut sim), expected);
+        }
+    }
+
+    #[test]
+    fn test_two_phase_matches_simple() {
+        let ((a_i, y), gates) = build_gates(|| {
+            let (a_i, a) = input(1);
+            (a_i, rising_edge(a.at(0)).output())
+        });
+
+        let mut simple = SimpleSimulator::new(&gates);
+        let mut two_phase = ChangeListSimulator::new(&gates);
+        two_phase.set_two_phase(true);
+        let mut in_place = ChangeListSimulator::new(&gates);
+
+        let mut pulses = 0;
+        let mut differs = false;
+
+        for t in 0..60 {
+            let a = ((t / 15) % 2) as u8;
+            simple.set(&a_i, a);
+            two_phase.set(&a_i, a);
+            in_place.set(&a_i, a);
+
+            simple.step();
+            two_phase.step();
+            in_place.step();
+
+            let expected: u8 = simple.get(&y);
+            assert_eq!(two_phase.get::<u8>(&y), expected, "t = {}", t);
+            pulses += expected as usize;
+            differs |= in_place.get::<u8>(&y) != expected;
+        }
+
+        assert!(pulses > 0);
+        assert!(differs);
+    }
+
+    #[test]
+    fn test_in_place() {
+        let (io, mut sim): (_, ChangeListSimulator) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+
+        let mut writes = 0;
+        for _ in 0..40 {
+            sim.run_cycles(1).unwrap();
+            writes += sim.get::<u8>(&io.w) as usize;
+            assert!(sim.probe_bus("pc").unwrap() < 8);
+        }
+        assert!(writes > 0);
+    }
+
+    #[test]
+    fn test_change_list_dedup() {
+        let ((a_i, b_i), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (b_i, b) = input(1);
+            nand(a.at(0), b.at(0)).output();
+            (a_i, b_i)
+        });
+
+        sim.set_two_phase(true);
+        sim.step_until_settled(100).unwrap();
+
+        sim.set(&a_i, 1u8);
+        sim.set(&b_i, 1u8);
+        assert_eq!(sim.change_list.len(), 1);
+
+        sim.step();
+        assert!(sim.is_settled());
+        assert!(sim.scheduled.words().iter().all(|&w| w == 0));
+    }
+
+    #[test]
+    fn test_changed_gates() {
+        let (io, gates) = build_gates(cpu_system);
+
+        for mode in 0..3 {
+            let mut sim = ChangeListSimulator::new(&gates);
+
+            match mode {
+                1 => sim.set_two_phase(true),
+                2 => {
+                    sim.set_partitions(3);
+                    sim.parallel_min_pending = 0;
+                },
+                _ => {},
+            }
+
+            sim.set(&io.rst, 1u8);
+            sim.step_until_settled(1000).unwrap();
+            sim.set(&io.rst, 0u8);
+
+            for t in 0..400 {
+                if t % 20 == 0 {
+                    sim.set(&io.clk, ((t / 20) % 2) as u8);
+                }
+
+                let before = sim.state.clone();
+                sim.step();
+
+                let changed = sim.changed_gates().unwrap();
+                assert!((0..before.len()).all(|i| before[i] == sim.state[i] || changed.contains(&(i as u32))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let path = std::env::temp_dir().join(format!("nand-state-{}", std::process::id()));
+        let (io, gates) = build_gates(cpu_system);
+
+        for partitions in [0, 3] {
+            let mut sim = ChangeListSimulator::new(&gates);
+            sim.set_partitions(partitions);
+
+            sim.set(&io.rst, 1u8);
+            sim.step_until_settled(1000).unwrap();
+            sim.set(&io.rst, 0u8);
+            run(&mut sim, &io, 31);
+
+            // save in the middle of a half clock so that the change list is not empty
+            sim.set(&io.clk, 1u8);
+            sim.step_by(3);
+            sim.snapshot();
+            sim.save_state(&path).unwrap();
+            sim.step_by(17);
+            let expected = run(&mut sim, &io, 100);
+
+            let mut loaded = ChangeListSimulator::new(&gates);
+            loaded.set_partitions(partitions);
+            loaded.load_state(&path).unwrap();
+            loaded.step_by(17);
+            assert_eq!(run(&mut loaded, &io, 100), expected);
+        }
+
+        let ((), mut other): ((), ChangeListSimulator) = build_simulator(|| {
+            let (_, a) = input(1);
+            (!a.at(0)).output();
+        });
+
+        let err = other.load_state(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut simple = SimpleSimulator::new(&gates);
+        let err = simple.load_state(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_diagnose_oscillation() {
+        let ((en_i, ring_i), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (en_i, en) = input(1);
+            let (ring_i, ring_en) = input(1);
+
+            // collapses to a single gate reading itself, with a named gate after it
+            let x = v();
+            let a = nand(en.at(0), x);
+            x << !!a;
+            (!a).name("osc").output();
+
+            // ring of three pinned inverters with a named gate in it
+            let r = v();
+            let r0 = nand(ring_en.at(0), r).pin();
+            let r1 = (!r0).pin().name("ring 1");
+            r << (!r1).pin();
+            r1.output();
+
+            // settles normally
+            let (_, d) = input(1);
+            (!d.at(0)).name("quiet").output();
+
+            (en_i, ring_i)
+        });
+
+        sim.step_until_settled(100).unwrap();
+        assert!(sim.diagnose_oscillation(100).is_empty());
+
+        sim.set(&en_i, 1u8);
+        assert_eq!(sim.step_until_settled(100), None);
+
+        let found = sim.diagnose_oscillation(100);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].signals, ["osc"]);
+        assert_eq!(found[0].distance, 1);
+
+        sim.set(&ring_i, 1u8);
+        let found = sim.diagnose_oscillation(100);
+        assert_eq!(found.len(), 2);
+
+        let ring = found.iter().find(|o| o.gates == 3).unwrap();
+        assert_eq!(ring.signals, ["ring 1"]);
+        assert_eq!(ring.distance, 0);
+        assert_eq!(ring.to_string(), format!("loop of 3 gates toggled {} times, contains ring 1", ring.toggles));
     }
 }
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::io;
+
+use crate::simulator::{BusFault, Input};
+use crate::simulator::state_file::{StateReader, StateWriter};
+
+/// Clock state kept by every simulator for `Simulator::run_cycles`
+#[derive(Clone)]
+pub struct Clock {
+    pub(super) input: Option<Input>,
+    pub(super) level: u8,
+    pub(super) cycles: u64,
+    pub(super) max_steps: usize,
+    pub(super) trace: bool,
+    ruler: String,
+    ruler_cycle: Option<u64>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            input: None,
+            level: 0,
+            cycles: 0,
+            max_steps: 1000,
+            trace: false,
+            ruler: String::new(),
+            ruler_cycle: None,
+        }
+    }
+}
+
+impl Clock {
+    /// Number of full clock cycles run
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Marks the start of each cycle in a row that lines up with the snapshot traces
+    pub fn snapshot(&mut self) {
+        if self.ruler_cycle == Some(self.cycles) {
+            self.ruler.push(' ');
+        } else {
+            self.ruler.push('|');
+            self.ruler_cycle = Some(self.cycles);
+        }
+    }
+
+    /// Prints the cycle row above the snapshot traces, if a clock is in use
+    pub fn show_ruler(&self, pad: usize) {
+        if self.input.is_some() {
+            println!("{name:pad$}{out}", name="cycle", pad=pad, out=self.ruler);
+        }
+    }
+
+    /// Prints the number of cycles run, if a clock is in use
+    pub fn show_cycles(&self) {
+        if self.input.is_some() {
+            println!("cycles: {}", self.cycles);
+        }
+    }
+
+    /// Number of snapshots taken, one per column of the cycle row
+    pub fn snapshots(&self) -> usize {
+        self.ruler.len()
+    }
+
+    /// Drops the snapshots after the first `snapshots` from the cycle row and the traces
+    pub(super) fn truncate_snapshots(&mut self, snapshots: usize, traces: &mut [String]) {
+        self.ruler.truncate(snapshots);
+
+        for trace in traces {
+            if let Some((end, _)) = trace.char_indices().nth(snapshots) {
+                trace.truncate(end);
+            }
+        }
+    }
+
+    /// Writes the cycle count and clock level as part of a simulator's saved state, along with the
+    /// cycle row and the snapshot traces unless they are left out
+    pub(super) fn save(&self, w: &mut StateWriter, traces: Option<&[String]>) {
+        w.u64(self.cycles);
+        w.u64(self.level as u64);
+        w.u64(self.ruler_cycle.map_or(0, |cycle| cycle + 1));
+        w.u64(traces.is_some() as u64);
+
+        if let Some(traces) = traces {
+            w.str(&self.ruler);
+            w.strs(traces);
+        }
+    }
+
+    /// Reads a clock and the `len` snapshot traces written with `save`. The registered input and
+    /// settings are kept, and so is the cycle row if the traces were left out, in which case they
+    /// are None.
+    pub(super) fn load(&self, r: &mut StateReader, len: usize) -> io::Result<(Clock, Option<Vec<String>>)> {
+        let cycles = r.u64()?;
+        let level = (r.u64()? != 0) as u8;
+        let ruler_cycle = r.u64()?.checked_sub(1);
+
+        let (ruler, traces) = if r.u64()? != 0 {
+            (r.str()?, Some(r.strs(len)?))
+        } else {
+            (self.ruler.clone(), None)
+        };
+
+        Ok((Clock { cycles, level, ruler, ruler_cycle, ..self.clone() }, traces))
+    }
+}
+
+/// Reason for `run_cycles`, `clock_edge` or the checked stepping functions of `Watched` to stop early
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The circuit did not settle within the limit set with `set_settle_limit`
+    NotSettled { cycle: u64 },
+
+    /// A breakpoint added with `Watched::add_breakpoint` fired
+    Breakpoint { id: usize, condition: String, value: u64, cycle: u64 },
+
+    /// A bus watched with `Watched::watch_bus` was contended or floating once the circuit settled
+    Bus { id: usize, fault: BusFault, cycle: u64 },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::NotSettled { cycle } => write!(f, "circuit did not settle in cycle {}", cycle),
+            Stop::Breakpoint { condition, value, cycle, .. } =>
+                write!(f, "{} in cycle {} (value {:#x})", condition, cycle, value),
+            Stop::Bus { fault, cycle, .. } => write!(f, "{} in cycle {}", fault, cycle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_run_cycles() {
+        let ((clk_i, rst_i, en_i, q), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (en_i, en) = input(1);
+
+            // counts on the falling edge
+            let q = vv(4);
+            let m = latch(increment(q), one(), clk.at(0), !rst.at(0));
+            q << latch(m, one(), !clk.at(0), !rst.at(0));
+
+            // oscillates while enabled
+            let x = v();
+            let a = nand(en.at(0), x);
+            x << !!a;
+            (!a).output();
+
+            (clk_i, rst_i, en_i, q.output())
+        });
+
+        sim.set_clock(&clk_i);
+        sim.set(&rst_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        sim.set(&rst_i, 0u8);
+        sim.step_until_settled(100).unwrap();
+        let start: u8 = sim.get(&q);
+
+        sim.run_cycles(5).unwrap();
+        assert_eq!(sim.cycles(), 5);
+        assert_eq!(sim.get::<u8>(&q), (start + 5) % 16);
+
+        sim.set(&en_i, 1u8);
+        sim.set_settle_limit(50);
+        assert_eq!(sim.run_cycles(3), Err(Stop::NotSettled { cycle: 5 }));
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::simulator::{Gate, Input, OptimizerConfig, Output};
+use crate::simulator::netlist::Netlist;
+use crate::simulator::levelize::{levelize, Levels};
+
+/// Generates a standalone Rust source file that simulates a netlist.
+///
+/// The generated struct holds two heap buffers with one `bool` per gate, the current state and the
+/// one before the last pass, and a straight-line `step` function that swaps them and evaluates the
+/// circuit with the same semantics as `LevelizedSimulator`, plus a setter for each
+/// registered `Input` and a getter for each registered `Output`. The file has no dependencies and
+/// can be included into any crate as a module.
+pub struct RustCodegen {
+    netlist: Netlist,
+    levels: Levels,
+    inputs: Vec<(String, Vec<usize>)>,
+    outputs: Vec<(String, Vec<usize>)>,
+}
+
+impl RustCodegen {
+    pub fn new(gates: &[Gate]) -> Self {
+        Self::with_optimizer(gates, &OptimizerConfig::default())
+    }
+
+    /// Generates code for the netlist built with the given optimizer settings instead of the
+    /// default ones
+    pub fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+        let levels = levelize(&netlist);
+
+        RustCodegen {
+            netlist,
+            levels,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Adds a `set_<name>` method for an input. Panics if the input is wider than 64 bits, or if
+    /// the name is not a valid identifier or already names another input.
+    pub fn input(mut self, name: &str, input: &Input) -> Self {
+        let name = ident(name, &self.inputs);
+        assert!(input.0.len() <= 64, "input {} has {} bits, more than 64", name, input.0.len());
+
+        let indices = input.0.iter().map(|&id| self.netlist.input_index(id)).collect();
+        self.inputs.push((name, indices));
+        self
+    }
+
+    /// Adds a `get_<name>` method for an output. Panics if the output is wider than 64 bits, or if
+    /// the name is not a valid identifier or already names another output.
+    pub fn output(mut self, name: &str, output: &Output) -> Self {
+        let name = ident(name, &self.outputs);
+        assert!(output.0.len() <= 64, "output {} has {} bits, more than 64", name, output.0.len());
+
+        let indices = output.0.iter().map(|&id| self.netlist.output_index(id)).collect();
+        self.outputs.push((name, indices));
+        self
+    }
+
+    pub fn generate(&self, struct_name: &str) -> String {
+        let mut out = String::new();
+        self.write_source(&mut out, struct_name).unwrap();
+        out
+    }
+
+    pub fn write(&self, struct_name: &str, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.generate(struct_name))
+    }
+
+    fn write_source(&self, out: &mut String, name: &str) -> std::fmt::Result {
+        let n = self.netlist.len();
+        let delays = &self.levels.delays;
+
+        writeln!(out, "// Generated by nand codegen. Do not edit.")?;
+        writeln!(out)?;
+        writeln!(out, "pub struct {} {{", name)?;
+        writeln!(out, "    s: Box<[bool]>,")?;
+        writeln!(out, "    p: Box<[bool]>,")?;
+        writeln!(out, "    d: Box<[bool]>,")?;
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "impl Default for {} {{", name)?;
+        writeln!(out, "    fn default() -> Self {{")?;
+        writeln!(out, "        Self::new()")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "#[allow(clippy::all, dead_code)]")?;
+        writeln!(out, "impl {} {{", name)?;
+        writeln!(out, "    pub const GATES: usize = {};", n)?;
+        writeln!(out)?;
+        writeln!(out, "    pub fn new() -> Self {{")?;
+        writeln!(out, "        {} {{", name)?;
+        writeln!(out, "            s: vec![false; {}].into_boxed_slice(),", n)?;
+        writeln!(out, "            p: vec![false; {}].into_boxed_slice(),", n)?;
+        writeln!(out, "            d: vec![false; {}].into_boxed_slice(),", delays.len())?;
+        writeln!(out, "        }}")?;
+        writeln!(out, "    }}")?;
+
+        for (input, indices) in &self.inputs {
+            let ty = uint_type(indices.len());
+
+            writeln!(out)?;
+            writeln!(out, "    pub fn set_{}(&mut self, value: {}) {{", input, ty)?;
+            for (bit, index) in indices.iter().enumerate() {
+                writeln!(out, "        self.s[{i}] = value & (1 << {}) != 0;", bit, i=index)?;
+                writeln!(out, "        self.p[{i}] = self.s[{i}];", i=index)?;
+            }
+            writeln!(out, "    }}")?;
+        }
+
+        for (output, indices) in &self.outputs {
+            let ty = uint_type(indices.len());
+
+            writeln!(out)?;
+            writeln!(out, "    pub fn get_{}(&self) -> {} {{", output, ty)?;
+            write!(out, "        0")?;
+            for (bit, index) in indices.iter().enumerate() {
+                write!(out, " | ((self.s[{}] as {}) << {})", index, ty, bit)?;
+            }
+            writeln!(out)?;
+            writeln!(out, "    }}")?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "    /// Runs the simulation until it settles or a maximum number of passes. Returns the")?;
+        writeln!(out, "    /// number of passes if it settled, or None if it didn't.")?;
+        writeln!(out, "    pub fn settle(&mut self, max_passes: usize) -> Option<usize> {{")?;
+        writeln!(out, "        (1..=max_passes).find(|_| !self.step())")?;
+        writeln!(out, "    }}")?;
+
+        writeln!(out)?;
+        writeln!(out, "    /// Runs the simulation for one pass over the circuit. Returns whether anything changed.")?;
+        writeln!(out, "    pub fn step(&mut self) -> bool {{")?;
+        writeln!(out, "        std::mem::swap(&mut self.s, &mut self.p);")?;
+        writeln!(out, "        let (s, p, d) = (&mut self.s, &self.p, &mut self.d);")?;
+        writeln!(out)?;
+
+        // Every non-input gate is written once per pass, so the buffer swapped in needs no copy.
+        // Gates not written yet in this pass are read from the previous state instead.
+        let mut done = vec![false; n];
+        done[..self.netlist.n_inputs].fill(true);
+
+        let read = |done: &[bool], index: u32| {
+            let buf = if done[index as usize] { 's' } else { 'p' };
+            format!("{}[{}]", buf, index)
+        };
+
+        for (i, &index) in delays.iter().enumerate() {
+            writeln!(out, "        s[{}] = d[{}];", index, i)?;
+            done[index as usize] = true;
+        }
+
+        for &index in &self.levels.order {
+            let (a, b) = self.netlist.gates[index as usize];
+            writeln!(out, "        s[{}] = !({} & {});", index, read(&done, a), read(&done, b))?;
+            done[index as usize] = true;
+        }
+
+        for (i, l) in self.levels.latches.iter().enumerate() {
+            writeln!(out, "        let l{} = (!{}, !{});", i, read(&done, l.x), read(&done, l.y))?;
+        }
+
+        for (i, l) in self.levels.latches.iter().enumerate() {
+            // set wins, then reset, otherwise hold
+            writeln!(
+                out,
+                "        (s[{q}], s[{qn}]) = (l{i}.0 || (!l{i}.1 && p[{q}]), l{i}.1 || (!l{i}.0 && !p[{q}]));",
+                q=l.q, qn=l.qn, i=i)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "        let mut changed = s != p;")?;
+
+        for (i, &index) in delays.iter().enumerate() {
+            let (a, b) = self.netlist.gates[index as usize];
+            writeln!(out, "        d[{}] = !(s[{}] & s[{}]);", i, a, b)?;
+            writeln!(out, "        changed |= d[{}] != s[{}];", i, index)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "        changed")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+
+        Ok(())
+    }
+}
+
+/// Turns a name into an identifier, with any other character than a letter or digit replaced by
+/// `_`. Panics if it starts with a digit or is empty, or is already one of the `used` names.
+fn ident(name: &str, used: &[(String, Vec<usize>)]) -> String {
+    let ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+
+    assert!(ident.starts_with(|c: char| !c.is_ascii_digit()), "{:?} is not a valid identifier", name);
+    assert!(used.iter().all(|(n, _)| *n != ident), "{:?} is already used as {}", name, ident);
+    ident
+}
+
+fn uint_type(bits: usize) -> &'static str {
+    match bits {
+        0..=8 => "u8",
+        9..=16 => "u16",
+        17..=32 => "u32",
+        _ => "u64",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Write;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use crate::modules::testing::cpu_system;
+    use crate::modules::*;
+    use crate::simulator::*;
+    use super::RustCodegen;
+
+    /// Removes a temporary directory when dropped, so that it goes away even if the test fails
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_cpu_matches_change_list() {
+        let half_clocks = 200;
+
+        let (io, gates) = build_gates(cpu_system);
+
+        let mut sim = ChangeListSimulator::new(&gates);
+        let mut expected = String::new();
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.step_until_settled(1000).unwrap();
+
+        for t in 0..half_clocks {
+            sim.set(&io.clk, (t % 2) as u8);
+            sim.step_until_settled(1000).unwrap();
+
+            let (addr, data, w): (u8, u8, u8) = (sim.get(&io.addr), sim.get(&io.data), sim.get(&io.w));
+            writeln!(expected, "{} {} {}", addr, data, w).unwrap();
+        }
+
+        let dir = TempDir(std::env::temp_dir().join(format!("nand-codegen-{}", std::process::id())));
+        let dir = &dir.0;
+        std::fs::create_dir_all(dir).unwrap();
+
+        RustCodegen::new(&gates)
+            .input("rst", &io.rst)
+            .input("clk", &io.clk)
+            .output("addr", &io.addr)
+            .output("data", &io.data)
+            .output("w", &io.w)
+            .write("Cpu", dir.join("cpu.rs"))
+            .unwrap();
+
+        std::fs::write(dir.join("main.rs"), format!(r#"
+            mod cpu;
+
+            fn main() {{
+                let mut c = cpu::Cpu::new();
+
+                c.set_rst(1);
+                c.settle(1000).unwrap();
+                c.set_rst(0);
+                c.settle(1000).unwrap();
+
+                for t in 0..{} {{
+                    c.set_clk((t % 2) as u8);
+                    c.settle(1000).unwrap();
+                    println!("{{}} {{}} {{}}", c.get_addr(), c.get_data(), c.get_w());
+                }}
+            }}
+        "#, half_clocks)).unwrap();
+
+        let status = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into()))
+            .args(["--edition", "2021", "-O", "-o"])
+            .arg(dir.join("cpu_sim"))
+            .arg(dir.join("main.rs"))
+            .status()
+            .unwrap();
+
+        assert!(status.success(), "generated code failed to compile");
+
+        let output = Command::new(dir.join("cpu_sim")).output().unwrap();
+
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_optimizer_config() {
+        let (_, gates) = build_gates(cpu_system);
+
+        let gate_count = |codegen: RustCodegen| {
+            let source = codegen.generate("Cpu");
+            let line = source.lines().find(|l| l.contains("pub const GATES")).unwrap();
+            line.trim_end_matches(';').rsplit(' ').next().unwrap().parse::<usize>().unwrap()
+        };
+
+        let optimized = gate_count(RustCodegen::new(&gates));
+        let unoptimized = gate_count(RustCodegen::with_optimizer(&gates, &OptimizerConfig::none()));
+        assert!(unoptimized > optimized);
+    }
+
+    #[test]
+    #[should_panic(expected = "input wide has 65 bits, more than 64")]
+    fn test_input_too_wide() {
+        let (a, gates) = build_gates(|| input(65).0);
+        RustCodegen::new(&gates).input("wide", &a);
+    }
+
+    #[test]
+    #[should_panic(expected = "\"2x\" is not a valid identifier")]
+    fn test_leading_digit() {
+        let (a, gates) = build_gates(|| input(1).0);
+        RustCodegen::new(&gates).input("2x", &a);
+    }
+
+    #[test]
+    #[should_panic(expected = "\"data out\" is already used as data_out")]
+    fn test_duplicate_name() {
+        let ((a, b), gates) = build_gates(|| (input(1).1.output(), input(1).1.output()));
+        RustCodegen::new(&gates).output("data_out", &a).output("data out", &b);
+    }
+}
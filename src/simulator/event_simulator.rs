@@ -0,0 +1,478 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+
+use crate::simulator::*;
+use crate::simulator::netlist::{Fanout, Netlist};
+use crate::simulator::bits::Bits;
+use crate::simulator::state_file::{StateReader, StateWriter};
+
+/// Timing-accurate event-driven simulator.
+///
+/// Each gate has a propagation delay, set with `V::delay` and defaulting to 1. When an input of a
+/// gate changes, the gate's new output value is scheduled to appear `delay` timesteps later. One
+/// `step` advances time by one timestep, so with all delays at 1 this behaves like
+/// `SimpleSimulator`.
+///
+/// `vary_delays` randomly perturbs the delays, which is useful for checking that a design does not
+/// depend on exact gate timing.
+pub struct EventSimulator {
+    now: u64,
+    state: Vec<u8>,
+    projected: Vec<u8>,
+    forced: Bits,
+    seq: u64,
+    queue: BinaryHeap<Reverse<(u64, u64, u32, u8)>>,
+    changed: Vec<u32>,
+    delay: Vec<u32>,
+    netlist: Netlist,
+    traces: Vec<String>,
+    clock: Clock,
+    fanout: Fanout,
+}
+
+impl EventSimulator {
+    /// Current simulation time in timesteps
+    pub fn time(&self) -> u64 {
+        self.now
+    }
+
+    /// Sets every gate's delay to its nominal delay plus a random amount in `-jitter..=jitter`,
+    /// keeping delays at least 1. The same seed always gives the same delays. A jitter of 0 restores
+    /// the nominal delays.
+    pub fn vary_delays(&mut self, seed: u64, jitter: u32) {
+        let mut rng = seed ^ 0x9e37_79b9_7f4a_7c15;
+
+        for (delay, &nominal) in self.delay.iter_mut().zip(self.netlist.delay.iter()) {
+            // xorshift64*
+            rng ^= rng >> 12;
+            rng ^= rng << 25;
+            rng ^= rng >> 27;
+            let r = rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32;
+
+            let offset = (r % (2 * jitter as u64 + 1)) as i64 - jitter as i64;
+            *delay = (nominal as i64 + offset).max(1) as u32;
+        }
+    }
+
+    /// Evaluates a gate and schedules its output to change if the new value differs from the last
+    /// scheduled one
+    fn schedule(&mut self, index: u32) {
+        if self.forced.get(index as usize) != 0 {
+            return;
+        }
+
+        let (a, b) = self.netlist.gates[index as usize];
+        let val = (self.state[a as usize] & self.state[b as usize]) ^ 0x01;
+
+        if val != self.projected[index as usize] {
+            // events for the same time are applied in the order they were scheduled
+            self.seq += 1;
+            self.projected[index as usize] = val;
+            self.queue.push(Reverse((self.now + self.delay[index as usize] as u64, self.seq, index, val)));
+        }
+    }
+}
+
+impl Simulator for EventSimulator {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+
+        let mut sim = EventSimulator {
+            now: 0,
+            state: vec![0; netlist.len()],
+            projected: vec![0; netlist.len()],
+            forced: Bits::new(netlist.len()),
+            seq: 0,
+            queue: BinaryHeap::new(),
+            changed: vec![],
+            delay: netlist.delay.clone(),
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            fanout: netlist.fanout(),
+            netlist,
+        };
+
+        for index in sim.netlist.n_inputs..sim.netlist.len() {
+            sim.schedule(index as u32);
+        }
+
+        sim
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        let bits = bits.into();
+
+        for (bit, id) in input.0.iter().copied().enumerate() {
+            let index = self.netlist.input_index(id);
+            let b = (bits & (1 << bit) != 0) as u8;
+
+            if self.state[index] != b && self.forced.get(index) == 0 {
+                self.state[index] = b;
+                self.projected[index] = b;
+
+                for i in 0..self.fanout[index].len() {
+                    self.schedule(self.fanout[index][i]);
+                }
+            }
+        }
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        let mut r = 0u64;
+
+        for (bit, id) in output.0.iter().copied().enumerate() {
+            let index = self.netlist.output_index(id);
+            r |= (self.state[index] as u64) << bit;
+        }
+
+        r.try_into().expect("output too long for data type")
+    }
+
+    /// Runs the simulation for one timestep
+    fn step(&mut self) {
+        self.now += 1;
+        self.changed.clear();
+
+        while let Some(&Reverse((time, _, index, val))) = self.queue.peek() {
+            if time > self.now {
+                break;
+            }
+
+            self.queue.pop();
+
+            if self.state[index as usize] != val {
+                self.state[index as usize] = val;
+                self.changed.push(index);
+            }
+        }
+
+        let changed = std::mem::take(&mut self.changed);
+
+        for &index in &changed {
+            for i in 0..self.fanout[index as usize].len() {
+                self.schedule(self.fanout[index as usize][i]);
+            }
+        }
+
+        self.changed = changed;
+    }
+
+    /// Runs the simulation until no more changes are scheduled or a maximum number of timesteps.
+    /// Returns the number of steps if the simulation settled within the allotted number of steps,
+    /// or None if it didn't.
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        let mut i = 0;
+        while i < max_steps {
+            i += 1;
+
+            self.step();
+
+            if self.queue.is_empty() {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            let v = self.state[*index] != 0;
+            out.push(if v { '█' } else { '▁' })
+        }
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        let mut events: Vec<_> = self.queue.iter().map(|e| e.0).collect();
+        events.sort_unstable();
+
+        let mut w = StateWriter::new("EventSimulator", self.netlist.fingerprint());
+        w.u64(self.now);
+        w.u64(self.seq);
+        w.u8s(&self.state);
+        w.u8s(&self.projected);
+        w.bits(&self.forced);
+        w.u32s(&self.delay);
+        w.u64s(&events.iter().map(|e| e.0).collect::<Vec<_>>());
+        w.u64s(&events.iter().map(|e| e.1).collect::<Vec<_>>());
+        w.u32s(&events.iter().map(|e| e.2).collect::<Vec<_>>());
+        w.u8s(&events.iter().map(|e| e.3).collect::<Vec<_>>());
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = self.netlist.len();
+
+        let mut r = StateReader::new(bytes, "EventSimulator", self.netlist.fingerprint())?;
+        let now = r.u64()?;
+        let seq = r.u64()?;
+        let state = r.values(len, 1)?;
+        let projected = r.values(len, 1)?;
+        let forced = r.bits(len)?;
+        let delay = r.u32s_len(len)?;
+        let times = r.u64s()?;
+        let seqs = r.u64s_len(times.len())?;
+        let indices = r.indices(self.netlist.n_inputs..len)?;
+        let vals = r.values(times.len(), 1)?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        if indices.len() != times.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "saved event queue is corrupt"));
+        }
+
+        self.now = now;
+        self.seq = seq;
+        self.state = state;
+        self.projected = projected;
+        self.forced = forced;
+        self.delay = delay;
+        self.queue = (0..times.len())
+            .map(|i| Reverse((times[i], seqs[i], indices[i], vals[i])))
+            .collect();
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
+    fn show(&self) {
+        let pad = self.netlist.name_pad();
+
+        self.clock.show_ruler(pad);
+
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
+            println!("{name:pad$}{out}", name=name, pad=pad, out=out);
+        }
+
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
+        println!("time: {}", self.now);
+    }
+
+    fn num_gates(&self) -> usize {
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.state[index]
+    }
+
+    fn changed_gates(&self) -> Option<&[u32]> {
+        Some(&self.changed)
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        match value {
+            Some(val) => {
+                self.forced.set(index, 1);
+                self.queue.retain(|e| e.0.2 as usize != index);
+                self.projected[index] = val;
+
+                if self.state[index] != val {
+                    self.state[index] = val;
+
+                    for i in 0..self.fanout[index].len() {
+                        self.schedule(self.fanout[index][i]);
+                    }
+                }
+            }
+            None => {
+                self.forced.set(index, 0);
+
+                if index >= self.netlist.n_inputs {
+                    self.schedule(index as u32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_delay() {
+        let ((a_i, y), mut sim): (_, EventSimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            (a_i, (!a.at(0)).delay(3).output())
+        });
+
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get::<u8>(&y), 1);
+
+        sim.set(&a_i, 1u8);
+
+        for _ in 0..2 {
+            sim.step();
+            assert_eq!(sim.get::<u8>(&y), 1);
+        }
+
+        sim.step();
+        assert_eq!(sim.get::<u8>(&y), 0);
+    }
+
+    #[test]
+    fn test_unit_delay_matches_simple() {
+        let build = || {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (data_i, data) = input(4);
+
+            let q = latch(data, one(), clk.at(0), !rst.at(0));
+            (clk_i, rst_i, data_i, q.output())
+        };
+
+        let ((clk_i, rst_i, data_i, q), mut event): (_, EventSimulator) = build_simulator(build);
+        let ((clk_j, rst_j, data_j, r), mut simple): (_, SimpleSimulator) = build_simulator(build);
+
+        let step_both = |event: &mut EventSimulator, simple: &mut SimpleSimulator| {
+            for _ in 0..20 {
+                event.step();
+                simple.step();
+                assert_eq!(event.get::<u8>(&q), simple.get::<u8>(&r));
+            }
+        };
+
+        event.set(&rst_i, 1u8);
+        simple.set(&rst_j, 1u8);
+        step_both(&mut event, &mut simple);
+        event.set(&rst_i, 0u8);
+        simple.set(&rst_j, 0u8);
+        step_both(&mut event, &mut simple);
+
+        for value in [0x5u8, 0xa, 0xf, 0x0] {
+            event.set(&data_i, value);
+            simple.set(&data_j, value);
+            event.set(&clk_i, 1u8);
+            simple.set(&clk_j, 1u8);
+            step_both(&mut event, &mut simple);
+            assert_eq!(event.get::<u8>(&q), value);
+
+            event.set(&clk_i, 0u8);
+            simple.set(&clk_j, 0u8);
+            step_both(&mut event, &mut simple);
+        }
+    }
+
+    #[test]
+    fn test_vary_delays_latch() {
+        let ((clk_i, rst_i, data_i, q), gates) = build_gates(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (data_i, data) = input(4);
+
+            let q = latch(data, one(), clk.at(0), !rst.at(0));
+            (clk_i, rst_i, data_i, q.output())
+        });
+
+        // Latches 4 values and returns whether every rising edge latched its value and no falling
+        // edge did, or None if the latch did not settle
+        let run = |seed: u64, jitter: u32| -> Option<bool> {
+            let mut sim = EventSimulator::new(&gates);
+            sim.vary_delays(seed, jitter);
+
+            sim.set(&rst_i, 1u8);
+            sim.step_until_settled(100).unwrap();
+            sim.set(&rst_i, 0u8);
+            sim.step_until_settled(100).unwrap();
+
+            let mut ok = true;
+
+            for value in [0x5u8, 0xa, 0xf, 0x0] {
+                sim.set(&data_i, value);
+                sim.step_until_settled(100).unwrap();
+
+                sim.set(&clk_i, 1u8);
+                sim.step_until_settled(100)?;
+                ok &= sim.get::<u8>(&q) == value;
+
+                sim.set(&data_i, !value & 0xf);
+                sim.set(&clk_i, 0u8);
+                sim.step_until_settled(100)?;
+                ok &= sim.get::<u8>(&q) == value;
+            }
+
+            Some(ok)
+        };
+
+        assert_eq!(run(0, 0), Some(true));
+
+        // Once its delays are off by one, the flip-flop's set input sometimes goes back up at the end
+        // of the enable pulse before its reset input sees the pulse end, so that reset glitches low
+        // right as set is released and the latch oscillates. That shows up as a latch that doesn't
+        // settle, never as a wrong value.
+        for seed in 0..16 {
+            assert_ne!(run(seed, 1), Some(false), "seed {} latched a wrong value", seed);
+        }
+
+        // The delays only depend on the seed, so with this latch seed 0 always latches every value
+        // and seed 9 always races
+        assert_eq!(run(0, 1), Some(true));
+        assert_eq!(run(9, 1), None);
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let path = std::env::temp_dir().join(format!("nand-event-state-{}", std::process::id()));
+
+        let ((clk_i, q), gates) = build_gates(|| {
+            let (clk_i, clk) = input(1);
+            let q = vv(4);
+            q << latch(increment(q), one(), clk.at(0), one());
+            (clk_i, q.output())
+        });
+
+        let run = |sim: &mut EventSimulator| -> Vec<u8> {
+            (0..40)
+                .map(|t| {
+                    sim.set(&clk_i, ((t / 10) % 2) as u8);
+                    sim.step();
+                    sim.get(&q)
+                })
+                .collect()
+        };
+
+        let mut sim = EventSimulator::new(&gates);
+        sim.vary_delays(3, 1);
+        run(&mut sim);
+        sim.set(&clk_i, 1u8);
+        sim.step();
+        sim.save_state(&path).unwrap();
+        let expected = run(&mut sim);
+
+        let mut loaded = EventSimulator::new(&gates);
+        loaded.load_state(&path).unwrap();
+        assert_eq!(loaded.time(), sim.time() - 40);
+        assert_eq!(run(&mut loaded), expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
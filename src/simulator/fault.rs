@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::simulator::Simulator;
+use crate::simulator::oscillation::{names_by_index, nearest_names};
+
+/// An input or gate output stuck at a constant value
+#[derive(Clone, Debug)]
+pub struct Fault {
+    /// ID of the faulty input or gate
+    pub gate: u32,
+
+    /// The value the gate is stuck at
+    pub stuck_at: u8,
+
+    /// The named signals nearest to the gate, or the gate's own name
+    pub signals: Vec<String>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gate {} stuck at {}", self.gate, self.stuck_at)?;
+
+        if !self.signals.is_empty() {
+            write!(f, " near {}", self.signals.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Result of running a testbench against every single stuck-at fault of a circuit
+#[derive(Clone, Debug)]
+pub struct FaultReport {
+    /// Number of faults simulated
+    pub faults: usize,
+
+    /// Faults that did not change the result of the testbench
+    pub undetected: Vec<Fault>,
+}
+
+impl FaultReport {
+    pub fn detected(&self) -> usize {
+        self.faults - self.undetected.len()
+    }
+
+    /// Fraction of faults detected, from 0 to 1
+    pub fn coverage(&self) -> f64 {
+        if self.faults == 0 {
+            return 1.0;
+        }
+
+        self.detected() as f64 / self.faults as f64
+    }
+
+    /// Undetected faults grouped by the named signal nearest to them. Faults with no named signal
+    /// nearby are listed under an empty name.
+    pub fn undetected_by_signal(&self) -> BTreeMap<String, Vec<&Fault>> {
+        let mut groups: BTreeMap<String, Vec<&Fault>> = BTreeMap::new();
+
+        for fault in &self.undetected {
+            groups.entry(fault.signals.join(", ")).or_default().push(fault);
+        }
+
+        groups
+    }
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "fault coverage: {:.1}% ({}/{})",
+            self.coverage() * 100.0,
+            self.detected(),
+            self.faults)?;
+
+        for (signal, faults) in self.undetected_by_signal() {
+            let sa0 = faults.iter().filter(|f| f.stuck_at == 0).count();
+            let sa1 = faults.len() - sa0;
+            let signal = if signal.is_empty() { "(unnamed)" } else { &signal };
+
+            writeln!(f, "  {}: {} undetected (stuck at 0: {}, stuck at 1: {})", signal, faults.len(), sa0, sa1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Measures how well a testbench detects manufacturing-style faults.
+///
+/// Every input and gate of the simulator's netlist is in turn forced to 0 and to 1 with
+/// `force_gate`, and `testbench` is run on the simulator from the state it was in when this was
+/// called. A fault is detected if the testbench returns something different than it does for the
+/// fault-free circuit, so the testbench should return everything it observes, for example a list
+/// of output values. The simulator is left in the state it started in.
+pub fn fault_coverage<S: Simulator, R: PartialEq>(
+    sim: &mut S,
+    mut testbench: impl FnMut(&mut S) -> R,
+) -> FaultReport {
+    let start = sim.save_state_bytes();
+    let restore = |sim: &mut S| sim.load_state_bytes(&start).expect("saved state does not load");
+
+    let expected = testbench(sim);
+
+    let netlist = sim.netlist();
+    let fanout = netlist.fanout();
+    let names = names_by_index(netlist);
+    let ids = netlist.ids.clone();
+
+    let mut report = FaultReport {
+        faults: 0,
+        undetected: Vec::new(),
+    };
+
+    // all but the constant zero
+    for (index, &id) in ids.iter().enumerate().skip(1) {
+        for stuck_at in [0, 1] {
+            restore(sim);
+            sim.force_gate(index, Some(stuck_at));
+
+            report.faults += 1;
+
+            if testbench(sim) == expected {
+                report.undetected.push(Fault {
+                    gate: id,
+                    stuck_at,
+                    signals: nearest_names(sim.netlist(), &fanout, &names, &[index as u32]).0,
+                });
+            }
+        }
+    }
+
+    restore(sim);
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    fn undetected(report: &FaultReport) -> Vec<(String, u8)> {
+        report.undetected
+            .iter()
+            .map(|f| (f.signals.join(", "), f.stuck_at))
+            .collect()
+    }
+
+    #[test]
+    fn test_fault_coverage() {
+        let ((a_i, b_i, y), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (b_i, b) = input(1);
+            let y = nand(a.at(0).name("a"), b.at(0).name("b")).name("y");
+            (a_i, b_i, y.output())
+        });
+
+        let mut run = |vectors: &[(u8, u8)]| fault_coverage(&mut sim, |sim| {
+            vectors
+                .iter()
+                .map(|&(a, b)| {
+                    sim.set(&a_i, a);
+                    sim.set(&b_i, b);
+                    sim.step_until_settled(100).map(|_| sim.get::<u8>(&y))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // a, b and y, each stuck at 0 and 1
+        let full = run(&[(0, 0), (0, 1), (1, 0), (1, 1)]);
+        assert_eq!(full.faults, 6);
+        assert_eq!(undetected(&full), []);
+
+        // b stuck at 1 gives the same outputs when b is always 1
+        let weak = run(&[(1, 1), (0, 1)]);
+        assert_eq!(weak.faults, 6);
+        assert_eq!(undetected(&weak), [("b".to_owned(), 1)]);
+
+        // with a always 0, only a stuck at 1 and y stuck at 0 change the output
+        let weaker = run(&[(0, 0), (0, 1)]);
+        assert_eq!(undetected(&weaker), [("a".to_owned(), 0), ("b".to_owned(), 0), ("b".to_owned(), 1), ("y".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn test_adder_fault_coverage() {
+        let ((a_i, b_i, s), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(4);
+            let (b_i, b) = input(4);
+            let (s, c) = adder(a, b, zero());
+            (a_i, b_i, s.name("s").iter().chain([c.name("c")]).vv().output())
+        });
+
+        let mut run = |vectors: &[(u8, u8)]| fault_coverage(&mut sim, |sim| {
+            vectors
+                .iter()
+                .map(|&(a, b)| {
+                    sim.set(&a_i, a);
+                    sim.set(&b_i, b);
+                    sim.step_until_settled(100).map(|_| sim.get::<u8>(&s))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let exhaustive: Vec<_> = (0..16).flat_map(|a| (0..16).map(move |b| (a, b))).collect();
+        let full = run(&exhaustive);
+        // with a carry in of zero, a few faults may be undetectable
+        assert!(full.coverage() > 0.95, "{}", full);
+
+        // adding zero never exercises a carry
+        let weak = run(&[(0, 0), (5, 0), (10, 0)]);
+        assert!(weak.coverage() < 0.8, "{}", weak);
+        assert!(weak.detected() < full.detected());
+        assert!(weak.undetected_by_signal().keys().any(|name| name.starts_with('c') || name.starts_with('s')));
+        assert_eq!(weak.faults, full.faults);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+
+use crate::simulator::netlist::{Fanout, Netlist};
+use crate::simulator::oscillation::{names_by_index, nearest_names};
+use crate::simulator::{Clock, Gate, Input, OptimizerConfig, Output, Simulator};
+
+/// A change of one input bit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    /// Name of the input bit, or `input <id>` if it has no name
+    pub input: String,
+
+    /// The new value
+    pub value: u8,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.input, if self.value != 0 { "rose" } else { "fell" })
+    }
+}
+
+/// A gate that changed more than once while the circuit settled
+#[derive(Clone, Debug)]
+pub struct Glitch {
+    /// The named signals nearest to the gate, or the gate's own name
+    pub signals: Vec<String>,
+
+    /// Number of gates between the gate and `signals`, or 0 if the gate itself is named
+    pub distance: usize,
+
+    /// Number of times the gate changed
+    pub changes: u32,
+
+    /// The input changes made since the circuit last settled
+    pub cause: Vec<Transition>,
+}
+
+impl fmt::Display for Glitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.distance == 0 {
+            write!(f, "{}", self.signals.join(", "))?;
+        } else {
+            write!(f, "{} gates from {}", self.distance, self.signals.join(", "))?;
+        }
+
+        write!(f, " changed {} times after", self.changes)?;
+
+        for (i, t) in self.cause.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, t)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counts gate changes between input changes and the circuit settling
+struct Counts {
+    names: Vec<Vec<String>>,
+    fanout: Fanout,
+    changes: Vec<u32>,
+    changed: Vec<u32>,
+    cause: Vec<Transition>,
+    glitches: Vec<Glitch>,
+}
+
+impl Counts {
+    fn new(netlist: &Netlist) -> Self {
+        Counts {
+            names: names_by_index(netlist),
+            fanout: netlist.fanout(),
+            changes: vec![0; netlist.len()],
+            changed: Vec::new(),
+            cause: Vec::new(),
+            glitches: Vec::new(),
+        }
+    }
+
+    /// Records an input change, or a forced gate, as a cause of the glitches until the circuit settles
+    fn input_changed(&mut self, netlist: &Netlist, index: usize, value: u8) {
+        let input = self.names[index]
+            .first()
+            .cloned()
+            .unwrap_or_else(|| format!("input {}", netlist.ids[index]));
+
+        self.cause.push(Transition { input, value });
+    }
+
+    /// Records a gate that changed in a step
+    fn gate_changed(&mut self, index: usize) {
+        if self.changes[index] == 0 {
+            self.changed.push(index as u32);
+        }
+
+        self.changes[index] += 1;
+    }
+
+    /// Drops the changes counted since the circuit last settled
+    fn forget(&mut self) {
+        for index in std::mem::take(&mut self.changed) {
+            self.changes[index as usize] = 0;
+        }
+
+        self.cause.clear();
+    }
+
+    /// Turns the gates that changed more than once into glitches once the circuit has settled
+    fn settled(&mut self, netlist: &Netlist) {
+        for index in std::mem::take(&mut self.changed) {
+            let changes = std::mem::take(&mut self.changes[index as usize]);
+
+            if changes > 1 {
+                let (signals, distance) = nearest_names(netlist, &self.fanout, &self.names, &[index]);
+
+                self.glitches.push(Glitch {
+                    signals,
+                    distance,
+                    changes,
+                    cause: self.cause.clone(),
+                });
+            }
+        }
+
+        self.cause.clear();
+    }
+}
+
+/// Records glitches in any simulator: gates that change more than once between an input change and
+/// the circuit settling again. Glitches caused by a rising clock can falsely clock latches.
+///
+/// After each step the gates that the simulator reports with `changed_gates` are compared with their
+/// values before it, or every gate for backends that don't report them, which is slower. Like
+/// `History`, it is a `Simulator` itself and only derefs to the wrapped one immutably.
+pub struct GlitchTracker<S> {
+    sim: S,
+    values: Vec<u8>,
+    counts: Counts,
+}
+
+impl<S: Simulator> GlitchTracker<S> {
+    pub fn wrap(sim: S) -> Self {
+        let netlist = sim.netlist();
+
+        GlitchTracker {
+            values: (0..netlist.len()).map(|index| sim.gate_value(index)).collect(),
+            counts: Counts::new(netlist),
+            sim,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sim
+    }
+
+    /// Returns the glitches recorded since the last call. Each one lists the input changes made
+    /// since the circuit last settled, so set one input at a time to pin down the cause.
+    pub fn take_glitches(&mut self) -> Vec<Glitch> {
+        std::mem::take(&mut self.counts.glitches)
+    }
+
+    /// Counts the gates that changed in a step, and turns them into glitches if it settled
+    fn stepped(&mut self, settled: bool) {
+        let (sim, values, counts) = (&self.sim, &mut self.values, &mut self.counts);
+        let netlist = sim.netlist();
+
+        let mut compare = |index: usize| {
+            let value = sim.gate_value(index);
+
+            if value != values[index] {
+                values[index] = value;
+                counts.gate_changed(index);
+            }
+        };
+
+        match sim.changed_gates() {
+            Some(changed) => changed.iter().for_each(|&index| compare(index as usize)),
+            None => (netlist.n_inputs..netlist.len()).for_each(compare),
+        }
+
+        if settled {
+            counts.settled(netlist);
+        }
+    }
+}
+
+impl<S: Simulator> Simulator for GlitchTracker<S> {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        GlitchTracker::wrap(S::with_optimizer(gates, config))
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        self.sim.set(input, bits);
+
+        let netlist = self.sim.netlist();
+
+        for &id in &input.0 {
+            let index = netlist.input_index(id);
+            let value = self.sim.gate_value(index);
+
+            if value != self.values[index] {
+                self.values[index] = value;
+                self.counts.input_changed(netlist, index, value);
+            }
+        }
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        self.sim.get(output)
+    }
+
+    fn step(&mut self) {
+        let settled = self.sim.step_until_settled(1).is_some();
+        self.stepped(settled);
+    }
+
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        for i in 1..=max_steps {
+            let settled = self.sim.step_until_settled(1).is_some();
+            self.stepped(settled);
+
+            if settled {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.sim.snapshot();
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        self.sim.save_state_bytes_with(traces)
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sim.load_state_bytes(bytes)?;
+
+        for (index, value) in self.values.iter_mut().enumerate() {
+            *value = self.sim.gate_value(index);
+        }
+
+        self.counts.forget();
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.sim.truncate_snapshots(snapshots);
+    }
+
+    fn show(&self) {
+        self.sim.show();
+    }
+
+    fn num_gates(&self) -> usize {
+        self.sim.num_gates()
+    }
+
+    fn clock(&self) -> &Clock {
+        self.sim.clock()
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        self.sim.clock_mut()
+    }
+
+    fn netlist(&self) -> &Netlist {
+        self.sim.netlist()
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.sim.gate_value(index)
+    }
+
+    fn changed_gates(&self) -> Option<&[u32]> {
+        self.sim.changed_gates()
+    }
+
+    /// Forcing a gate to a new value counts as an input change
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        self.sim.force_gate(index, value);
+
+        let value = self.sim.gate_value(index);
+
+        if value != self.values[index] {
+            self.values[index] = value;
+            self.counts.input_changed(self.sim.netlist(), index, value);
+        }
+    }
+}
+
+impl<S> Deref for GlitchTracker<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.sim
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    fn check_glitches<S: Simulator>() {
+        let ((a_i, b_i), mut sim): (_, GlitchTracker<S>) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (b_i, b) = input(1);
+            let (a, b) = (a.at(0).name("a"), b.at(0).name("b"));
+
+            // static-1 hazard when a changes while b is high
+            let y = ((a & b) | (!a & b)).name("y");
+            y.output();
+
+            (a_i, b_i)
+        });
+
+        // gates can toggle while settling from the all-zero start state
+        sim.step_until_settled(100).unwrap();
+        sim.take_glitches();
+
+        sim.set(&a_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        sim.set(&b_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert!(sim.take_glitches().is_empty());
+
+        sim.set(&a_i, 0u8);
+        sim.step_until_settled(100).unwrap();
+        let glitches = sim.take_glitches();
+
+        let y = glitches.iter().find(|g| g.distance == 0 && g.signals == ["y"]).unwrap();
+        assert_eq!(y.changes, 2);
+        assert_eq!(y.cause, [Transition { input: "a".into(), value: 0 }]);
+        assert_eq!(y.to_string(), "y changed 2 times after a fell");
+    }
+
+    #[test]
+    fn test_glitches() {
+        check_glitches::<ChangeListSimulator>();
+        check_glitches::<SimpleSimulator>();
+        check_glitches::<EventSimulator>();
+    }
+}
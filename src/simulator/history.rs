@@ -0,0 +1,399 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem::take;
+use std::ops::Deref;
+
+use crate::simulator::{Clock, Gate, Input, Netlist, OptimizerConfig, Output, Simulator};
+
+/// Something done to the simulator that is replayed when rewinding
+enum Op {
+    Set(Input, u64),
+    Force(usize, Option<u8>),
+    Step,
+    Snapshot,
+}
+
+/// A saved state without the snapshot traces, the number of snapshots taken by then, and
+/// everything done to the simulator after it along with the clock level and cycle count at the time
+struct Checkpoint {
+    time: u64,
+    state: Vec<u8>,
+    snapshots: usize,
+    ops: Vec<(Op, u8, u64)>,
+}
+
+/// Keeps a bounded history of a simulator so that it can be rewound with `step_back` and
+/// `goto_time`.
+///
+/// Every so many steps the state of the simulator is saved, and the inputs set, gates forced or
+/// released and snapshots taken in between are logged. Rewinding restores the nearest saved state
+/// before the target step and replays the log up to it, so this works with any backend.
+///
+/// The saved states leave out the snapshot traces, which only grow. Rewinding cuts them back to
+/// the snapshots taken by the saved state instead, and the replayed ones are taken again.
+///
+/// `History` is a `Simulator` itself, so it can be built with `build_simulator` and run with
+/// `run_cycles` or `Watched`. It only derefs to the wrapped simulator immutably, since changes
+/// made directly to it would not be logged.
+pub struct History<S> {
+    sim: S,
+    time: u64,
+    limit: usize,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl<S: Simulator> History<S> {
+    /// Wraps a simulator, with history turned off until `set_history`
+    pub fn wrap(sim: S) -> Self {
+        History {
+            sim,
+            time: 0,
+            limit: 0,
+            checkpoints: VecDeque::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sim
+    }
+
+    /// Number of steps run since the simulator was wrapped
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Starts keeping at least the last `limit` steps, discarding any history so far. A limit of 0
+    /// turns history off.
+    pub fn set_history(&mut self, limit: usize) {
+        self.limit = limit;
+        self.checkpoints.clear();
+
+        if limit != 0 {
+            self.checkpoint();
+        }
+    }
+
+    /// Number of steps that can currently be undone
+    pub fn history_len(&self) -> usize {
+        self.checkpoints.front().map_or(0, |c| (self.time - c.time) as usize)
+    }
+
+    /// Undoes up to `steps` steps, along with any inputs set or gates forced after the last one, and
+    /// returns the number of steps undone
+    pub fn step_back(&mut self, steps: usize) -> usize {
+        if self.checkpoints.is_empty() {
+            return 0;
+        }
+
+        let steps = steps.min(self.history_len());
+        self.rewind(self.time - steps as u64);
+        steps
+    }
+
+    /// Rewinds or runs the simulation to the state right after step `time`. Returns false without
+    /// changing anything if that is further back than the history goes.
+    pub fn goto_time(&mut self, time: u64) -> bool {
+        if time < self.time {
+            if self.time - time > self.history_len() as u64 {
+                return false;
+            }
+
+            self.rewind(time);
+        } else {
+            while self.time < time {
+                self.step();
+            }
+        }
+
+        true
+    }
+
+    /// Number of steps between saved states, so that rewinding replays at most this many steps
+    fn interval(&self) -> u64 {
+        (self.limit as u64 / 16).max(1)
+    }
+
+    fn checkpoint(&mut self) {
+        self.checkpoints.push_back(Checkpoint {
+            time: self.time,
+            state: self.sim.save_state_bytes_with(false),
+            snapshots: self.sim.snapshots(),
+            ops: Vec::new(),
+        });
+    }
+
+    fn log(&mut self, op: Op) {
+        if let Some(c) = self.checkpoints.back_mut() {
+            let clock = self.sim.clock();
+            c.ops.push((op, clock.level, clock.cycles));
+        }
+    }
+
+    /// Logs a step that was run, and saves the state if it is time to
+    fn stepped(&mut self) {
+        self.time += 1;
+        self.log(Op::Step);
+
+        let Some(last) = self.checkpoints.back() else {
+            return;
+        };
+
+        if self.time - last.time >= self.interval() {
+            self.checkpoint();
+
+            while self.checkpoints.len() > 1 && self.time - self.checkpoints[1].time >= self.limit as u64 {
+                self.checkpoints.pop_front();
+            }
+        }
+    }
+
+    /// Restores the last saved state at or before step `time`, and replays the log after it up to
+    /// that step and any snapshots taken right after it
+    fn rewind(&mut self, time: u64) {
+        while self.checkpoints.back().is_some_and(|c| c.time > time) {
+            self.checkpoints.pop_back();
+        }
+
+        let c = self.checkpoints.back_mut().expect("rewinding past the history");
+        let ops = take(&mut c.ops);
+
+        self.sim.load_state_bytes(&c.state).expect("saved state does not load");
+        self.sim.truncate_snapshots(c.snapshots);
+        self.time = c.time;
+
+        for (op, level, cycles) in ops {
+            if self.time == time && !matches!(op, Op::Snapshot) {
+                break;
+            }
+
+            let clock = self.sim.clock_mut();
+            clock.level = level;
+            clock.cycles = cycles;
+
+            match op {
+                Op::Set(input, bits) => self.set(&input, bits),
+                Op::Force(index, value) => self.force_gate(index, value),
+                Op::Step => self.step(),
+                Op::Snapshot => self.snapshot(),
+            }
+        }
+    }
+}
+
+impl<S: Simulator> Simulator for History<S> {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        History::wrap(S::with_optimizer(gates, config))
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        let bits = bits.into();
+        self.sim.set(input, bits);
+        self.log(Op::Set(input.clone(), bits));
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        self.sim.get(output)
+    }
+
+    fn step(&mut self) {
+        self.sim.step();
+        self.stepped();
+    }
+
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        for i in 1..=max_steps {
+            let settled = self.sim.step_until_settled(1).is_some();
+            self.stepped();
+
+            if settled {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.sim.snapshot();
+        self.log(Op::Snapshot);
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        self.sim.save_state_bytes_with(traces)
+    }
+
+    /// Restores a saved state of the wrapped simulator, and starts a new history from it
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sim.load_state_bytes(bytes)?;
+        self.set_history(self.limit);
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.sim.truncate_snapshots(snapshots);
+    }
+
+    fn show(&self) {
+        self.sim.show();
+    }
+
+    fn num_gates(&self) -> usize {
+        self.sim.num_gates()
+    }
+
+    fn clock(&self) -> &Clock {
+        self.sim.clock()
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        self.sim.clock_mut()
+    }
+
+    fn netlist(&self) -> &Netlist {
+        self.sim.netlist()
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.sim.gate_value(index)
+    }
+
+    fn changed_gates(&self) -> Option<&[u32]> {
+        self.sim.changed_gates()
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        self.sim.force_gate(index, value);
+        self.log(Op::Force(index, value));
+    }
+}
+
+impl<S> Deref for History<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.sim
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::cpu_system;
+    use crate::simulator::*;
+
+    fn gate_values<S: Simulator>(sim: &S) -> Vec<u8> {
+        (0..sim.netlist().len()).map(|index| sim.gate_value(index)).collect()
+    }
+
+    fn check_step_back<S: Simulator>() {
+        let (io, mut sim): (_, History<S>) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_history(500);
+
+        let start = sim.time();
+        let mut states = vec![];
+
+        for t in 0..800 {
+            if t % 20 == 0 {
+                sim.set(&io.clk, ((t / 20) % 2) as u8);
+            }
+
+            sim.step();
+            states.push(gate_values(&sim));
+        }
+
+        assert!((500..600).contains(&sim.history_len()));
+        assert!(!sim.goto_time(start + 200));
+
+        assert_eq!(sim.step_back(1), 1);
+        assert_eq!(gate_values(&sim), states[798]);
+
+        assert!(sim.goto_time(start + 400));
+        assert_eq!(gate_values(&sim), states[399]);
+
+        // running forward again with the same inputs gives the same states
+        for (t, state) in states.iter().enumerate().skip(400) {
+            if t % 20 == 0 {
+                sim.set(&io.clk, ((t / 20) % 2) as u8);
+            }
+
+            sim.step();
+            assert_eq!(gate_values(&sim), *state);
+        }
+
+        assert_eq!(sim.time(), start + 800);
+    }
+
+    #[test]
+    fn test_step_back() {
+        check_step_back::<ChangeListSimulator>();
+        check_step_back::<LevelizedSimulator>();
+        check_step_back::<EventSimulator>();
+    }
+
+    #[test]
+    fn test_step_back_clock() {
+        let (io, mut sim): (_, History<ChangeListSimulator>) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+        sim.set_clock_trace(true);
+        sim.set_history(1000);
+
+        sim.run_cycles(3).unwrap();
+        let (time, pc) = (sim.time(), sim.probe_bus("pc").unwrap());
+        let state = sim.save_state_bytes();
+
+        sim.run_cycles(4).unwrap();
+        assert_eq!(sim.cycles(), 7);
+
+        assert!(sim.goto_time(time));
+        assert_eq!(sim.cycles(), 3);
+        assert_eq!(sim.probe_bus("pc").unwrap(), pc);
+
+        // the clock level, cycle count and traces are back where they were
+        assert_eq!(sim.save_state_bytes(), state);
+    }
+
+    fn check_step_back_force<S: Simulator>() {
+        let (io, mut sim): (_, History<S>) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+        sim.set_history(1000);
+        sim.run_cycles(2).unwrap();
+
+        let state = sim.save_state_bytes();
+
+        // undoing the step also undoes the force, flag included
+        sim.force("pc", 2).unwrap();
+        sim.step();
+        assert_eq!(sim.step_back(1), 1);
+        assert_eq!(sim.save_state_bytes(), state);
+
+        sim.force("pc", 2).unwrap();
+        sim.step_by(3);
+        sim.release("pc").unwrap();
+        sim.step();
+
+        // back to before the release, so the pc is held again
+        assert_eq!(sim.step_back(1), 1);
+        sim.run_cycles(5).unwrap();
+        assert_eq!(sim.probe_bus("pc").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_step_back_force() {
+        check_step_back_force::<ChangeListSimulator>();
+        check_step_back_force::<LevelizedSimulator>();
+        check_step_back_force::<EventSimulator>();
+    }
+}
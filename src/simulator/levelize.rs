@@ -0,0 +1,165 @@
+use crate::simulator::netlist::Netlist;
+
+/// Cross-coupled NAND pair `q = nand(x, qn)`, `qn = nand(y, q)` as built by `d_flipflop`
+#[derive(Copy, Clone, Debug)]
+pub struct Latch {
+    pub q: u32,
+    pub qn: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Evaluation schedule for a netlist where feedback loops are cut open into state elements.
+pub struct Levels {
+    /// Cross-coupled NAND pairs. Each pair is evaluated at once as an SR latch after the
+    /// combinational logic, so every gate reads the value the latch had at the start of the pass.
+    pub latches: Vec<Latch>,
+
+    /// Pinned gates that read a non-pinned gate. A chain of pinned gates, as built by
+    /// `rising_edge`, acts as a single one-pass delay: its head is sampled at the end of a pass and
+    /// the rest of the chain is evaluated from it in the next one.
+    pub delays: Vec<u32>,
+
+    /// The remaining non-input gates in topological order. Evaluating them in this order in place
+    /// propagates a change through all combinational logic in one pass.
+    pub order: Vec<u32>,
+
+    /// Gates in `order` that close a feedback loop not made by a latch, and are therefore read
+    /// before they are evaluated. Their value carries over between passes.
+    pub feedback: Vec<u32>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mark {
+    New,
+    Active,
+    Done,
+}
+
+/// Orders the gates of a netlist for levelized evaluation.
+///
+/// First pairs up cross-coupled NANDs into latches and finds the heads of pinned gate chains.
+/// Then does a depth-first search from every other gate towards its inputs and emits gates in
+/// post-order. Inputs, latches and delay heads end the search, and any input found on the current
+/// search path is a back edge that closes a loop, which is recorded as a feedback gate.
+pub fn levelize(netlist: &Netlist) -> Levels {
+    let n = netlist.len();
+    let gates = &netlist.gates;
+
+    let is_gate = |index: u32| index as usize >= netlist.n_inputs;
+
+    let mut mark = vec![Mark::New; n];
+    mark[..netlist.n_inputs].fill(Mark::Done);
+
+    let mut latches = Vec::new();
+
+    for q in netlist.n_inputs..n {
+        if mark[q] != Mark::New || netlist.pinned[q] {
+            continue;
+        }
+
+        let (a, b) = gates[q];
+
+        let pair = [(a, b), (b, a)]
+            .into_iter()
+            .find(|&(qn, x)| {
+                qn != x
+                    && is_gate(qn)
+                    && qn as usize != q
+                    && mark[qn as usize] == Mark::New
+                    && !netlist.pinned[qn as usize]
+                    && {
+                        let (c, d) = gates[qn as usize];
+                        (c == q as u32) != (d == q as u32)
+                    }
+            });
+
+        if let Some((qn, x)) = pair {
+            let (c, d) = gates[qn as usize];
+            let y = if c == q as u32 { d } else { c };
+
+            mark[q] = Mark::Done;
+            mark[qn as usize] = Mark::Done;
+            latches.push(Latch { q: q as u32, qn, x, y });
+        }
+    }
+
+    let delays: Vec<u32> = (netlist.n_inputs..n)
+        .filter(|&index| {
+            let (a, b) = gates[index];
+            netlist.pinned[index]
+                && mark[index] == Mark::New
+                && !(netlist.pinned[a as usize] && netlist.pinned[b as usize])
+        })
+        .map(|index| index as u32)
+        .collect();
+
+    for &index in &delays {
+        mark[index as usize] = Mark::Done;
+    }
+
+    let mut order = Vec::with_capacity(n);
+    let mut feedback = Vec::new();
+    let mut stack: Vec<(u32, u8)> = Vec::new();
+
+    for root in netlist.n_inputs..n {
+        if mark[root] != Mark::New {
+            continue;
+        }
+
+        mark[root] = Mark::Active;
+        stack.push((root as u32, 0));
+
+        while let Some((index, next)) = stack.last_mut() {
+            let index = *index;
+            let (a, b) = gates[index as usize];
+
+            let input = match *next {
+                0 => Some(a),
+                1 => Some(b),
+                _ => None,
+            };
+
+            *next += 1;
+
+            match input {
+                Some(input) => match mark[input as usize] {
+                    Mark::New => {
+                        mark[input as usize] = Mark::Active;
+                        stack.push((input, 0));
+                    },
+                    Mark::Active => {
+                        feedback.push(input);
+                    },
+                    Mark::Done => {},
+                },
+                None => {
+                    mark[index as usize] = Mark::Done;
+                    order.push(index);
+                    stack.pop();
+                },
+            }
+        }
+    }
+
+    feedback.sort_unstable();
+    feedback.dedup();
+
+    Levels {
+        latches,
+        delays,
+        order,
+        feedback,
+    }
+}
+
+/// Evaluates an SR latch made of two cross-coupled NANDs `q = nand(x, qn)` and `qn = nand(y, q)`,
+/// returning the new `(q, qn)`. When both inputs are high the latch holds `q`.
+pub fn eval_latch(x: u8, y: u8, q: u8) -> (u8, u8) {
+    match (x, y) {
+        (0, 0) => (1, 1),
+        (0, _) => (1, 0),
+        (_, 0) => (0, 1),
+        _ => (q, q ^ 0x01),
+    }
+}
@@ -0,0 +1,481 @@
+use std::io;
+
+use crate::simulator::*;
+use crate::simulator::netlist::Netlist;
+use crate::simulator::bits::Bits;
+use crate::simulator::levelize::{eval_latch, levelize, Levels};
+use crate::simulator::state_file::{StateReader, StateWriter};
+
+/// Cycle-based simulator that evaluates the whole circuit in topological order once per step.
+///
+/// The cross-coupled NANDs in `d_flipflop` are turned into latches that are updated together at
+/// the end of each pass, and the combinational logic between them is evaluated in topological
+/// order, so a change propagates through all of it in a single step instead of one gate level per
+/// step. A chain of pinned gates such as the one in `rising_edge` is kept as a one-step delay, so
+/// a clock edge produces a pulse exactly one pass wide and the design settles in a few passes.
+///
+/// Only gates and latches with a changed input are evaluated. They are tracked in a bitset indexed
+/// by their position in the topological order followed by the latches, which is scanned in order
+/// once per pass.
+pub struct LevelizedSimulator {
+    state: Vec<u8>,
+    delay_buf: Vec<u8>,
+    forced: Bits,
+    changed: bool,
+    dirty: Vec<u64>,
+    deferred: Vec<u64>,
+    /// Inputs and index of each gate in the topological order, by position
+    ops: Vec<(u32, u32, u32)>,
+    /// Positions of the readers of each gate. The readers from `fanout_start` up to `fanout_split`
+    /// come later in the same pass, and the rest are evaluated in the next pass.
+    fanout_start: Vec<u32>,
+    fanout_split: Vec<u32>,
+    fanout: Vec<u32>,
+    netlist: Netlist,
+    levels: Levels,
+    traces: Vec<String>,
+    clock: Clock,
+}
+
+const NOT_ORDERED: u32 = u32::MAX;
+
+impl LevelizedSimulator {
+    /// Number of gates that hold state between passes
+    pub fn num_state_bits(&self) -> usize {
+        self.levels.latches.len() + self.levels.feedback.len() + self.levels.delays.len()
+    }
+
+    /// Marks the gates reading a gate for evaluation in the current pass
+    fn mark_fanout(&mut self, index: u32) {
+        let start = self.fanout_start[index as usize] as usize;
+        let end = self.fanout_start[index as usize + 1] as usize;
+
+        for &pos in &self.fanout[start..end] {
+            self.dirty[pos as usize / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    /// Marks every gate and latch for evaluation in the current pass
+    fn mark_all(&mut self) {
+        let len = self.levels.order.len() + self.levels.latches.len();
+        self.dirty.fill(!0);
+
+        if !len.is_multiple_of(64) {
+            self.dirty[len / 64] = (1 << (len % 64)) - 1;
+        }
+
+        self.changed = true;
+    }
+}
+
+impl Simulator for LevelizedSimulator {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+        let levels = levelize(&netlist);
+
+        let mut position = vec![NOT_ORDERED; netlist.len()];
+        for (pos, &index) in levels.order.iter().enumerate() {
+            position[index as usize] = pos as u32;
+        }
+        for (i, l) in levels.latches.iter().enumerate() {
+            position[l.q as usize] = (levels.order.len() + i) as u32;
+            position[l.qn as usize] = (levels.order.len() + i) as u32;
+        }
+
+        let readers_of = netlist.fanout();
+        let mut fanout_start = vec![0u32];
+        let mut fanout_split = Vec::with_capacity(netlist.len());
+        let mut fanout = Vec::new();
+        for index in 0..readers_of.len() {
+            let pos = position[index];
+            let is_gate = (pos as usize) < levels.order.len();
+
+            let mut readers: Vec<u32> = readers_of[index]
+                .iter()
+                .map(|&r| position[r as usize])
+                .filter(|&rpos| rpos != NOT_ORDERED && rpos != pos)
+                .collect();
+
+            readers.sort_unstable();
+            readers.dedup();
+
+            // readers after a gate in the order see its new value in the same pass, while the
+            // readers of a latch or a feedback gate see it in the next one
+            let later = match pos {
+                NOT_ORDERED => readers.len(),
+                _ if is_gate => readers.iter().filter(|&&rpos| rpos > pos).count(),
+                _ => 0,
+            };
+            let n_readers = readers.len();
+            readers.rotate_left(n_readers - later);
+
+            fanout_split.push(fanout.len() as u32 + later as u32);
+            fanout.extend(readers);
+            fanout_start.push(fanout.len() as u32);
+        }
+
+        let ops = levels.order
+            .iter()
+            .map(|&index| {
+                let (a, b) = netlist.gates[index as usize];
+                (a, b, index)
+            })
+            .collect();
+
+        let words = (levels.order.len() + levels.latches.len()).div_ceil(64);
+
+        let mut sim = LevelizedSimulator {
+            state: vec![0; netlist.len()],
+            delay_buf: vec![0; levels.delays.len()],
+            forced: Bits::new(netlist.len()),
+            changed: true,
+            dirty: vec![0; words],
+            deferred: vec![0; words],
+            ops,
+            fanout_start,
+            fanout_split,
+            fanout,
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            netlist,
+            levels,
+        };
+
+        sim.mark_all();
+        sim
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        let bits = bits.into();
+
+        for (bit, id) in input.0.iter().copied().enumerate() {
+            let index = self.netlist.input_index(id);
+            let b = bits & (1 << bit) != 0;
+
+            if self.state[index] != b as u8 && self.forced.get(index) == 0 {
+                self.state[index] = b as u8;
+                self.mark_fanout(index as u32);
+                self.changed = true;
+            }
+        }
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        let mut r = 0u64;
+
+        for (bit, id) in output.0.iter().copied().enumerate() {
+            let index = self.netlist.output_index(id);
+            r |= (self.state[index] as u64) << bit;
+        }
+
+        r.try_into().expect("output too long for data type")
+    }
+
+    /// Runs the simulation for one pass over the circuit
+    fn step(&mut self) {
+        // a pass that changed nothing left nothing to evaluate
+        if !self.changed {
+            return;
+        }
+
+        let mut changed = false;
+
+        for i in 0..self.levels.delays.len() {
+            let index = self.levels.delays[i];
+
+            if self.delay_buf[i] != self.state[index as usize] && self.forced.get(index as usize) == 0 {
+                self.state[index as usize] = self.delay_buf[i];
+                self.mark_fanout(index);
+                changed = true;
+            }
+        }
+
+        let LevelizedSimulator { state, forced, dirty, deferred, ops, levels, fanout_start, fanout_split, fanout, .. } = self;
+        let n_ops = ops.len();
+
+        let mut word = 0;
+        while word < dirty.len() {
+            if dirty[word] == 0 {
+                word += 1;
+                continue;
+            }
+
+            let pos = word * 64 + dirty[word].trailing_zeros() as usize;
+            dirty[word] &= dirty[word] - 1;
+
+            let (a, b) = if pos < n_ops {
+                let (a, b, index) = ops[pos];
+                let val = (state[a as usize] & state[b as usize]) ^ 0x01;
+
+                if val == state[index as usize] || forced.get(index as usize) != 0 {
+                    continue;
+                }
+
+                state[index as usize] = val;
+                (Some(index), None)
+            } else {
+                let l = levels.latches[pos - n_ops];
+                let (x, y) = (state[l.x as usize], state[l.y as usize]);
+
+                // a forced side of the latch holds, and the other side is a plain gate reading it
+                let (q, qn) = match (forced.get(l.q as usize), forced.get(l.qn as usize)) {
+                    (0, 0) => eval_latch(x, y, state[l.q as usize]),
+                    (_, 0) => (state[l.q as usize], (y & state[l.q as usize]) ^ 0x01),
+                    (0, _) => ((x & state[l.qn as usize]) ^ 0x01, state[l.qn as usize]),
+                    _ => (state[l.q as usize], state[l.qn as usize]),
+                };
+
+                let q_changed = q != state[l.q as usize];
+                let qn_changed = qn != state[l.qn as usize];
+                state[l.q as usize] = q;
+                state[l.qn as usize] = qn;
+
+                (q_changed.then_some(l.q), qn_changed.then_some(l.qn))
+            };
+
+            for index in a.into_iter().chain(b) {
+                changed = true;
+
+                let start = fanout_start[index as usize] as usize;
+                let split = fanout_split[index as usize] as usize;
+                let end = fanout_start[index as usize + 1] as usize;
+
+                for &rpos in &fanout[start..split] {
+                    dirty[rpos as usize / 64] |= 1 << (rpos % 64);
+                }
+
+                // feedback or latch output, evaluated in the next pass
+                for &rpos in &fanout[split..end] {
+                    deferred[rpos as usize / 64] |= 1 << (rpos % 64);
+                }
+            }
+        }
+
+        std::mem::swap(&mut self.dirty, &mut self.deferred);
+
+        for (out, &index) in self.delay_buf.iter_mut().zip(self.levels.delays.iter()) {
+            let g = self.netlist.gates[index as usize];
+            *out = match self.forced.get(index as usize) {
+                0 => (self.state[g.0 as usize] & self.state[g.1 as usize]) ^ 0x01,
+                _ => self.state[index as usize],
+            };
+
+            if *out != self.state[index as usize] {
+                changed = true;
+            }
+        }
+
+        self.changed = changed;
+    }
+
+    /// Runs the simulation until a pass changes nothing or a maximum number of passes. Returns the
+    /// number of passes if the simulation settled within the allotted number of passes, or None if
+    /// it didn't.
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        let mut i = 0;
+        while i < max_steps {
+            i += 1;
+
+            self.step();
+
+            if !self.changed {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            let v = self.state[*index] != 0;
+            out.push(if v { '█' } else { '▁' })
+        }
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        // deferred is always empty between passes
+
+        let mut w = StateWriter::new("LevelizedSimulator", self.netlist.fingerprint());
+        w.u8s(&self.state);
+        w.u8s(&self.delay_buf);
+        w.bits(&self.forced);
+        w.u64(self.changed as u64);
+        w.u64s(&self.dirty);
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut r = StateReader::new(bytes, "LevelizedSimulator", self.netlist.fingerprint())?;
+        let state = r.values(self.netlist.len(), 1)?;
+        let delay_buf = r.values(self.levels.delays.len(), 1)?;
+        let forced = r.bits(self.netlist.len())?;
+        let changed = r.u64()? != 0;
+        let dirty = r.bits(self.levels.order.len() + self.levels.latches.len())?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        self.state = state;
+        self.delay_buf = delay_buf;
+        self.forced = forced;
+        self.changed = changed;
+        self.dirty.copy_from_slice(dirty.words());
+        self.deferred.fill(0);
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
+    fn show(&self) {
+        let pad = self.netlist.name_pad();
+
+        self.clock.show_ruler(pad);
+
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
+            println!("{name:pad$}{out}", name=name, pad=pad, out=out);
+        }
+
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
+        println!("state bits: {}", self.num_state_bits());
+    }
+
+    fn num_gates(&self) -> usize {
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.state[index]
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        match value {
+            Some(val) => {
+                self.forced.set(index, 1);
+
+                if self.state[index] != val {
+                    self.state[index] = val;
+                    self.mark_fanout(index as u32);
+                    self.changed = true;
+                }
+            }
+            None => {
+                self.forced.set(index, 0);
+
+                // the gate is wherever it is in the order, so it's simplest to start over
+                if index >= self.netlist.n_inputs {
+                    self.mark_all();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::cpu_system;
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_cpu_matches_change_list() {
+        let (io, gates) = build_gates(cpu_system);
+
+        let mut sims: Lockstep<ChangeListSimulator, LevelizedSimulator> = Lockstep::new(&gates);
+        sims.set(&io.rst, 1u8);
+        sims.settle().unwrap();
+        sims.set(&io.rst, 0u8);
+        sims.set_clock(&io.clk);
+        sims.run_cycles(200).unwrap();
+    }
+
+    #[test]
+    fn test_cpu_matches_simple() {
+        let (io, gates) = build_gates(cpu_system);
+
+        let mut sims: Lockstep<SimpleSimulator, LevelizedSimulator> = Lockstep::new(&gates);
+        sims.set(&io.rst, 1u8);
+        sims.settle().unwrap();
+        sims.set(&io.rst, 0u8);
+        sims.set_clock(&io.clk);
+        sims.run_cycles(100).unwrap();
+    }
+
+    #[test]
+    fn test_load_dirty_out_of_range() {
+        let (_, gates) = build_gates(cpu_system);
+        let mut sim = LevelizedSimulator::new(&gates);
+
+        // a pass marked past the last gate and latch, as in a corrupt file
+        let len = sim.levels.order.len() + sim.levels.latches.len();
+        assert!(!len.is_multiple_of(64));
+        sim.dirty[len / 64] |= 1 << (len % 64);
+
+        let err = LevelizedSimulator::new(&gates).load_state_bytes(&sim.save_state_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_latch() {
+        let ((clk_i, rst_i, data_i, q), mut sim): (_, LevelizedSimulator) = build_simulator(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (data_i, data) = input(4);
+
+            let q = latch(data, one(), clk.at(0), !rst.at(0));
+            (clk_i, rst_i, data_i, q.output())
+        });
+
+        assert!(sim.num_state_bits() > 0);
+
+        sim.set(&rst_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        sim.set(&rst_i, 0u8);
+        sim.step_until_settled(100).unwrap();
+
+        assert_eq!(sim.get::<u8>(&q), 0);
+
+        let mut prev = 0u8;
+
+        for value in [0x5u8, 0xa, 0xf, 0x0] {
+            sim.set(&data_i, value);
+            sim.step_until_settled(100).unwrap();
+            assert_eq!(sim.get::<u8>(&q), prev);
+
+            sim.set(&clk_i, 1u8);
+            sim.step_until_settled(100).unwrap();
+            assert_eq!(sim.get::<u8>(&q), value);
+
+            sim.set(&clk_i, 0u8);
+            sim.step_until_settled(100).unwrap();
+            assert_eq!(sim.get::<u8>(&q), value);
+
+            prev = value;
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::simulator::{Gate, Input, Simulator, Stop};
+
+/// Name of a simulator type without its module path
+fn backend_name<S>() -> &'static str {
+    let name = std::any::type_name::<S>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+fn wave(v: u8) -> char {
+    match v {
+        0 => '▁',
+        1 => '█',
+        _ => '▒',
+    }
+}
+
+/// The first comparison at which two backends disagreed
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Names of the two backends
+    pub backends: (&'static str, &'static str),
+
+    /// Clock cycle of the first backend, or 0 without a clock
+    pub cycle: u64,
+
+    /// Number of comparisons made before this one
+    pub compared: u64,
+
+    /// Signals that differ, with the value of each in the two backends
+    pub signals: Vec<(String, u8, u8)>,
+
+    /// Recent values of each differing signal in the two backends, oldest first
+    pub waves: Vec<(String, String, String)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} and {} diverged in cycle {} after {} comparisons:",
+            self.backends.0,
+            self.backends.1,
+            self.cycle,
+            self.compared)?;
+
+        let pad = self.waves.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0) + 1;
+        let bpad = self.backends.0.len().max(self.backends.1.len()) + 1;
+
+        for (name, a, b) in &self.waves {
+            writeln!(f, "  {:pad$}{:bpad$}{}", name, self.backends.0, a, pad=pad, bpad=bpad)?;
+            writeln!(f, "  {:pad$}{:bpad$}{}", "", self.backends.1, b, pad=pad, bpad=bpad)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reason for a `Lockstep` run to stop early
+#[derive(Clone, Debug)]
+pub enum LockstepError {
+    /// One of the backends stopped, for example because it did not settle
+    Stopped { backend: &'static str, stop: Stop },
+
+    Diverged(Divergence),
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockstepError::Stopped { backend, stop } => write!(f, "{} stopped: {}", backend, stop),
+            LockstepError::Diverged(d) => write!(f, "{}", d),
+        }
+    }
+}
+
+/// Runs the same circuit in two backends side by side with identical inputs, and compares every
+/// output and named signal each time the circuit has settled. Use it to find backend bugs and
+/// circuits whose behavior depends on the order in which gates are evaluated.
+pub struct Lockstep<A: Simulator, B: Simulator> {
+    pub a: A,
+    pub b: B,
+    /// Names and netlist indices of the compared signals
+    signals: Vec<(String, usize)>,
+    max_steps: usize,
+    window: usize,
+    history: VecDeque<(Vec<u8>, Vec<u8>)>,
+    compared: u64,
+}
+
+impl<A: Simulator, B: Simulator> Lockstep<A, B> {
+    pub fn new(gates: &[Gate]) -> Self {
+        let a = A::new(gates);
+        let b = B::new(gates);
+
+        // both backends build the same netlist from the same gates
+        let netlist = a.netlist();
+
+        let mut signals: Vec<(String, usize)> = netlist.output_map
+            .iter()
+            .map(|(id, index)| (format!("output {}", id), index))
+            .collect();
+
+        for (index, name) in &netlist.names {
+            match signals.iter_mut().find(|(_, i)| i == index) {
+                Some(s) if s.0.starts_with("output ") => s.0 = name.clone(),
+                Some(_) => (),
+                None => signals.push((name.clone(), *index)),
+            }
+        }
+
+        Lockstep {
+            a,
+            b,
+            signals,
+            max_steps: 1000,
+            window: 16,
+            history: VecDeque::new(),
+            compared: 0,
+        }
+    }
+
+    /// Sets the maximum number of timesteps the backends may take to settle. Defaults to 1000.
+    pub fn set_settle_limit(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+        self.a.set_settle_limit(max_steps);
+        self.b.set_settle_limit(max_steps);
+    }
+
+    /// Sets how many comparisons are shown in the waveform of a divergence. Defaults to 16.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+    }
+
+    pub fn set(&mut self, input: &Input, bits: impl Into<u64> + Copy) {
+        self.a.set(input, bits);
+        self.b.set(input, bits);
+    }
+
+    pub fn set_clock(&mut self, clk: &Input) {
+        self.a.set_clock(clk);
+        self.b.set_clock(clk);
+    }
+
+    /// Runs both backends until they settle, then compares them
+    pub fn settle(&mut self) -> Result<(), LockstepError> {
+        if self.a.step_until_settled(self.max_steps).is_none() {
+            let stop = Stop::NotSettled { cycle: self.a.cycles() };
+            return Err(LockstepError::Stopped { backend: backend_name::<A>(), stop });
+        }
+
+        if self.b.step_until_settled(self.max_steps).is_none() {
+            let stop = Stop::NotSettled { cycle: self.b.cycles() };
+            return Err(LockstepError::Stopped { backend: backend_name::<B>(), stop });
+        }
+
+        self.compare()
+    }
+
+    /// Runs a clock edge in both backends, then compares them
+    pub fn clock_edge(&mut self) -> Result<(), LockstepError> {
+        self.a.clock_edge().map_err(|stop| LockstepError::Stopped { backend: backend_name::<A>(), stop })?;
+        self.b.clock_edge().map_err(|stop| LockstepError::Stopped { backend: backend_name::<B>(), stop })?;
+        self.compare()
+    }
+
+    /// Runs full clock cycles in both backends, comparing them after each edge
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), LockstepError> {
+        for _ in 0..cycles {
+            self.clock_edge()?;
+            self.clock_edge()?;
+        }
+
+        Ok(())
+    }
+
+    /// Compares every output and named signal of the two backends
+    pub fn compare(&mut self) -> Result<(), LockstepError> {
+        let sample_a: Vec<u8> = self.signals.iter().map(|&(_, index)| self.a.gate_value(index)).collect();
+        let sample_b: Vec<u8> = self.signals.iter().map(|&(_, index)| self.b.gate_value(index)).collect();
+
+        let differ = sample_a != sample_b;
+
+        if self.history.len() == self.window {
+            self.history.pop_front();
+        }
+
+        self.history.push_back((sample_a, sample_b));
+
+        if !differ {
+            self.compared += 1;
+            return Ok(());
+        }
+
+        let (sample_a, sample_b) = self.history.back().unwrap();
+        let mut signals = Vec::new();
+        let mut waves = Vec::new();
+
+        for (i, (name, _)) in self.signals.iter().enumerate() {
+            if sample_a[i] != sample_b[i] {
+                signals.push((name.clone(), sample_a[i], sample_b[i]));
+                waves.push((
+                    name.clone(),
+                    self.history.iter().map(|(a, _)| wave(a[i])).collect(),
+                    self.history.iter().map(|(_, b)| wave(b[i])).collect(),
+                ));
+            }
+        }
+
+        Err(LockstepError::Diverged(Divergence {
+            backends: (backend_name::<A>(), backend_name::<B>()),
+            cycle: self.a.cycles(),
+            compared: self.compared,
+            signals,
+            waves,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::cpu_system;
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_backends_agree_on_cpu() {
+        let (io, gates) = build_gates(cpu_system);
+
+        let mut sims: Lockstep<SimpleSimulator, ChangeListSimulator> = Lockstep::new(&gates);
+        sims.set(&io.rst, 1u8);
+        sims.settle().unwrap();
+        sims.set(&io.rst, 0u8);
+        sims.set_clock(&io.clk);
+        sims.run_cycles(100).unwrap();
+    }
+
+    #[test]
+    fn test_race_diverges() {
+        // both latch inputs released at once, so which side wins depends on evaluation order
+        let ((sn_i, rn_i), gates) = build_gates(|| {
+            let (sn_i, sn) = input(1);
+            let (rn_i, rn) = input(1);
+
+            let qn = v();
+            let q = nand(sn.at(0), qn).name("q");
+            qn << nand(rn.at(0), q).name("qn");
+
+            (sn_i, rn_i)
+        });
+
+        let mut sims: Lockstep<ChangeListSimulator, LevelizedSimulator> = Lockstep::new(&gates);
+        sims.settle().unwrap();
+
+        sims.set(&sn_i, 1u8);
+        sims.set(&rn_i, 1u8);
+
+        let LockstepError::Diverged(d) = sims.settle().unwrap_err() else {
+            panic!("expected a divergence");
+        };
+
+        assert_eq!(d.backends, ("ChangeListSimulator", "LevelizedSimulator"));
+        assert_eq!(d.compared, 1);
+        assert!(d.signals.iter().any(|(name, _, _)| name == "q"));
+        assert_eq!(d.waves[0].1.chars().count(), 2);
+        assert!(d.to_string().contains("diverged in cycle 0"), "{}", d);
+    }
+
+    #[test]
+    fn test_not_settled_cycle() {
+        let ((sn_i, rn_i), gates) = build_gates(|| {
+            let (sn_i, sn) = input(1);
+            let (rn_i, rn) = input(1);
+
+            let qn = v();
+            let q = nand(sn.at(0), qn);
+            qn << nand(rn.at(0), q);
+
+            (sn_i, rn_i)
+        });
+
+        // the race settles one way with a change list, but oscillates when every gate steps at once
+        let mut sims: Lockstep<ChangeListSimulator, SimpleSimulator> = Lockstep::new(&gates);
+        sims.settle().unwrap();
+        sims.b.clock_mut().cycles = 3;
+
+        sims.set(&sn_i, 1u8);
+        sims.set(&rn_i, 1u8);
+
+        match sims.settle() {
+            Err(LockstepError::Stopped { backend: "SimpleSimulator", stop: Stop::NotSettled { cycle: 3 } }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
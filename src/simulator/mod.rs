@@ -1,26 +1,72 @@
 pub mod builder;
 
+#[allow(clippy::module_inception)]
 pub mod simulator;
 pub use simulator::*;
 
+mod netlist;
+pub use netlist::{Fanout, IdMap, Netlist};
+mod bits;
+mod state_file;
+mod oscillation;
+pub use oscillation::Oscillation;
+
+mod bus;
+pub use bus::{BusFault, BusMonitor};
+
+mod fault;
+pub use fault::{fault_coverage, Fault, FaultReport};
+
+mod clock;
+pub use clock::{Clock, Stop};
+mod watch;
+pub use watch::{Condition, Signal, WatchError, Watched, Watches};
+mod lockstep;
+pub use lockstep::{Divergence, Lockstep, LockstepError};
+mod history;
+pub use history::History;
+mod glitch;
+pub use glitch::{Glitch, GlitchTracker, Transition};
+
 mod simple_simulator;
 pub use simple_simulator::SimpleSimulator;
 
 mod change_list_simulator;
 pub use change_list_simulator::ChangeListSimulator;
 
+mod bit_parallel_simulator;
+pub use bit_parallel_simulator::{BitParallelSimulator, LANES};
+
+mod levelize;
+
+mod levelized_simulator;
+pub use levelized_simulator::LevelizedSimulator;
+
+mod event_simulator;
+pub use event_simulator::EventSimulator;
+
+mod ternary_simulator;
+pub use ternary_simulator::TernarySimulator;
+
 pub mod v;
 
 mod test;
 
 mod optimizer;
+pub use optimizer::{OptimizerConfig, OptimizerReport};
 
-pub use test::bench;
+pub mod codegen;
+
+pub use test::{bench, bench_settled};
 
 pub fn build_simulator<S: Simulator, R>(f: impl FnOnce() -> R) -> (R, S) {
     builder::GateBuilder::default().build_simulator::<S, R>(f)
 }
 
+pub fn build_gates<R>(f: impl FnOnce() -> R) -> (R, Vec<Gate>) {
+    builder::GateBuilder::default().build_gates(f)
+}
+
 pub fn build_combinatorial_test<R>(f: impl FnOnce() -> R) -> (R, ChangeListSimulator) {
     let (r, mut sim) = builder::GateBuilder::default().build_simulator::<ChangeListSimulator, _>(f);
 
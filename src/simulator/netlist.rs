@@ -0,0 +1,272 @@
+use std::ops::Index;
+
+use crate::simulator::{Gate, OptimizerConfig, OptimizerReport};
+
+const NONE: u32 = u32::MAX;
+
+/// Optimized and index-mapped form of a gate list, shared by the simulator backends.
+///
+/// Gates are sorted so that all inputs come first, and every gate is referred to by its index in
+/// the sorted list instead of its original ID.
+pub struct Netlist {
+    pub gates: Vec<(u32, u32)>,
+    /// Original ID of each gate
+    pub ids: Vec<u32>,
+    pub names: Vec<(usize, String)>,
+    pub input_map: IdMap,
+    pub output_map: IdMap,
+    pub pinned: Vec<bool>,
+    pub delay: Vec<u32>,
+    pub n_inputs: usize,
+    /// What the optimizer did to the gates
+    pub optimizer: OptimizerReport,
+}
+
+/// Gate index by input or output ID, as a flat array indexed by the ID
+#[derive(Default)]
+pub struct IdMap(Vec<u32>);
+
+impl IdMap {
+    fn insert(&mut self, id: u32, index: usize) {
+        if self.0.len() <= id as usize {
+            self.0.resize(id as usize + 1, NONE);
+        }
+
+        self.0[id as usize] = index as u32;
+    }
+
+    pub fn get(&self, id: u32) -> Option<usize> {
+        self.0.get(id as usize).filter(|&&index| index != NONE).map(|&index| index as usize)
+    }
+
+    /// The IDs and gate indices, in order of ID
+    pub fn iter(&self) -> impl Iterator<Item=(u32, usize)> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, &index)| index != NONE)
+            .map(|(id, &index)| (id as u32, index as usize))
+    }
+}
+
+/// Indices of the non-input gates that read each gate's output, stored as one flat array
+pub struct Fanout {
+    start: Vec<u32>,
+    readers: Vec<u32>,
+}
+
+impl Fanout {
+    pub fn len(&self) -> usize {
+        self.start.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Index<usize> for Fanout {
+    type Output = [u32];
+
+    #[inline]
+    fn index(&self, index: usize) -> &[u32] {
+        &self.readers[self.start[index] as usize..self.start[index + 1] as usize]
+    }
+}
+
+impl Netlist {
+    pub fn new(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let mut gates = gates.to_vec();
+
+        let optimizer = super::optimizer::optimize_gates(&mut gates, config);
+
+        gates.sort_by_key(|g| (
+            std::cmp::Reverse(g.is_input()),
+            g.id,
+        ));
+
+        // gate IDs are allocated densely by the builder, so a flat array can map them to indices
+        let max_id = gates.iter().map(|g| g.id.max(g.a).max(g.b)).max().unwrap_or(0);
+        let mut index_of = vec![NONE; max_id as usize + 1];
+        for (index, g) in gates.iter().enumerate() {
+            index_of[g.id as usize] = index as u32;
+        }
+
+        let index = |id: u32| match index_of[id as usize] {
+            NONE => panic!("gate reads gate {} which is not in the list", id),
+            index => index,
+        };
+
+        let n_inputs = gates
+            .iter()
+            .take_while(|g| g.is_input())
+            .count();
+
+        let mut names = Vec::new();
+        let mut input_map = IdMap::default();
+        let mut output_map = IdMap::default();
+
+        for (index, g) in gates.iter().enumerate() {
+            let Some(meta) = g.meta() else {
+                continue;
+            };
+
+            for name in &meta.names {
+                names.push((index, name.clone()));
+            }
+
+            if let Some(id) = meta.input_id {
+                input_map.insert(id, index);
+            }
+
+            if let Some(id) = meta.output_id {
+                output_map.insert(id, index);
+            }
+        }
+
+        Netlist {
+            names,
+            input_map,
+            output_map,
+            pinned: gates
+                .iter()
+                .map(|g| g.is_pinned())
+                .collect(),
+            delay: gates
+                .iter()
+                .map(|g| g.delay())
+                .collect(),
+            n_inputs,
+            optimizer,
+            ids: gates
+                .iter()
+                .map(|g| g.id)
+                .collect(),
+            gates: gates
+                .iter()
+                .map(|g| (index(g.a), index(g.b)))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.gates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gates.is_empty()
+    }
+
+    /// Returns the indices of the non-input gates that read each gate's output
+    pub fn fanout(&self) -> Fanout {
+        let mut start = vec![0u32; self.gates.len() + 1];
+
+        for &(a, b) in &self.gates[self.n_inputs..] {
+            start[a as usize + 1] += 1;
+
+            if b != a {
+                start[b as usize + 1] += 1;
+            }
+        }
+
+        for index in 0..self.gates.len() {
+            start[index + 1] += start[index];
+        }
+
+        let mut next = start.clone();
+        let mut readers = vec![0u32; start[self.gates.len()] as usize];
+
+        for (index, &(a, b)) in self.gates.iter().enumerate().skip(self.n_inputs) {
+            readers[next[a as usize] as usize] = index as u32;
+            next[a as usize] += 1;
+
+            if b != a {
+                readers[next[b as usize] as usize] = index as u32;
+                next[b as usize] += 1;
+            }
+        }
+
+        Fanout { start, readers }
+    }
+
+    pub fn input_index(&self, id: u32) -> usize {
+        self.input_map.get(id).unwrap()
+    }
+
+    pub fn output_index(&self, id: u32) -> usize {
+        self.output_map.get(id).unwrap()
+    }
+
+    /// Hash of everything that determines the layout and behavior of the netlist, used to check that
+    /// saved state belongs to it
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, which unlike the std hashers is guaranteed to stay the same between builds
+        let mut h = 0xcbf2_9ce4_8422_2325u64;
+        let mut add = |v: u64| {
+            for byte in v.to_le_bytes() {
+                h = (h ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+
+        add(self.gates.len() as u64);
+        add(self.n_inputs as u64);
+
+        for (index, &(a, b)) in self.gates.iter().enumerate() {
+            add(a as u64 | (b as u64) << 32);
+            add(self.pinned[index] as u64 | (self.delay[index] as u64) << 1);
+        }
+
+        for (map, tag) in [(&self.input_map, 1), (&self.output_map, 2)] {
+            for (id, index) in map.iter() {
+                add(tag);
+                add(id as u64 | (index as u64) << 32);
+            }
+        }
+
+        for (index, name) in &self.names {
+            add(*index as u64);
+            name.bytes().for_each(|b| add(b as u64));
+        }
+
+        h
+    }
+
+    pub fn name_pad(&self) -> usize {
+        // leave room for the cycle row
+        self.names.iter().map(|(_, name)| name.len()).max().unwrap_or(0).max("cycle".len()) + 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::cpu_system;
+    use crate::simulator::*;
+    use super::Netlist;
+
+    #[test]
+    fn test_fanout() {
+        let (_, gates) = build_gates(cpu_system);
+        let netlist = Netlist::new(&gates, &OptimizerConfig::default());
+        let fanout = netlist.fanout();
+
+        assert_eq!(fanout.len(), netlist.len());
+
+        for index in 0..netlist.len() {
+            let expected: Vec<u32> = (netlist.n_inputs..netlist.len())
+                .filter(|&r| netlist.gates[r].0 as usize == index || netlist.gates[r].1 as usize == index)
+                .map(|r| r as u32)
+                .collect();
+
+            assert_eq!(&fanout[index], expected.as_slice(), "gate {}", index);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "gate reads gate 3")]
+    fn test_missing_gate() {
+        let gate = |id, a, b| Gate { id, a, b, meta: None };
+        let gates = [gate(0, 0, 0), gate(1, 0, 0), gate(2, 1, 3), gate(4, 2, 2)];
+
+        Netlist::new(&gates, &OptimizerConfig::none());
+    }
+}
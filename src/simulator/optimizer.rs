@@ -1,124 +1,486 @@
+use std::collections::HashMap;
+use std::fmt;
+
 use super::simulator::Gate;
 
-pub fn optimize_gates(gates: &mut Vec<Gate>) {
-    println!("pruning {} gates", gates.len());
+const NONE: u32 = u32::MAX;
 
-    loop {
-        let len_before = gates.len();
+/// Which rewrites the optimizer applies. The default enables all of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OptimizerConfig {
+    /// Simplify gates that read constant 0 or 1
+    pub fold_constants: bool,
 
-        for cur in gates.iter_mut() {
-            if cur.meta().is_none() && (cur.a == 0 || cur.b == 0) {
-                // simplify nand(a, 0), nand(0, b) -> nand(0, 0)
-                // which can be potentially be combined with others later
+    /// Merge gates with the same inputs and delay
+    pub merge_duplicates: bool,
+
+    /// Replace !!a with a
+    pub remove_double_negations: bool,
+
+    /// Remove gates whose output isn't read
+    pub remove_dead: bool,
+}
+
+impl OptimizerConfig {
+    pub fn all() -> Self {
+        OptimizerConfig {
+            fold_constants: true,
+            merge_duplicates: true,
+            remove_double_negations: true,
+            remove_dead: true,
+        }
+    }
+
+    /// Leaves the gates exactly as built, which is useful for debugging the circuit or the optimizer
+    pub fn none() -> Self {
+        OptimizerConfig {
+            fold_constants: false,
+            merge_duplicates: false,
+            remove_double_negations: false,
+            remove_dead: false,
+        }
+    }
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        OptimizerConfig::all()
+    }
+}
+
+/// What the optimizer did to a circuit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OptimizerReport {
+    pub gates_before: usize,
+    pub gates_after: usize,
 
-                cur.a = 0;
-                cur.b = 0;
+    /// Gates whose inputs were simplified by constant folding. They aren't removed by it directly,
+    /// but usually end up as duplicates or dead gates.
+    pub constants_folded: usize,
+
+    pub duplicates_merged: usize,
+    pub double_negations_removed: usize,
+    pub dead_removed: usize,
+}
+
+impl fmt::Display for OptimizerReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "optimized {} gates to {}: {} constants folded, {} duplicates merged, {} double negations \
+            removed, {} dead gates removed",
+            self.gates_before,
+            self.gates_after,
+            self.constants_folded,
+            self.duplicates_merged,
+            self.double_negations_removed,
+            self.dead_removed)
+    }
+}
+
+/// Gates with their readers and reference counts, indexed by gate ID
+struct Graph {
+    gates: Vec<Gate>,
+    /// Position of each live gate in `gates` by ID
+    pos: Vec<u32>,
+    /// Number of gate inputs connected to each gate, by ID
+    refs: Vec<u32>,
+    /// Gates that read each gate, by ID. May contain gates that no longer do.
+    readers: Vec<Vec<u32>>,
+    /// A gate for each pair of inputs and delay, used to find duplicates
+    structure: HashMap<(u32, u32, u32), u32>,
+    worklist: Vec<u32>,
+    queued: Vec<bool>,
+    config: OptimizerConfig,
+    report: OptimizerReport,
+    /// Whether unused gates with names are kept for now
+    keep_named: bool,
+}
+
+impl Graph {
+    fn new(gates: Vec<Gate>, config: OptimizerConfig) -> Self {
+        let len = gates.iter().map(|g| g.id.max(g.a).max(g.b)).max().map_or(0, |id| id as usize + 1);
+
+        let mut graph = Graph {
+            pos: vec![NONE; len],
+            refs: vec![0; len],
+            readers: vec![vec![]; len],
+            structure: HashMap::with_capacity(gates.len()),
+            worklist: gates.iter().rev().map(|g| g.id).collect(),
+            queued: vec![true; len],
+            config,
+            report: OptimizerReport { gates_before: gates.len(), ..Default::default() },
+            keep_named: false,
+            gates,
+        };
+
+        for (pos, g) in graph.gates.iter().enumerate() {
+            graph.pos[g.id as usize] = pos as u32;
+            graph.refs[g.a as usize] += 1;
+            graph.refs[g.b as usize] += 1;
+            graph.readers[g.a as usize].push(g.id);
+
+            if g.b != g.a {
+                graph.readers[g.b as usize].push(g.id);
             }
         }
 
-        // see if there is a hardwired 1 gate which is NOT 0
-        // eventually there should only be one left
+        graph
+    }
+
+    fn gate(&self, id: u32) -> Option<&Gate> {
+        match self.pos[id as usize] {
+            NONE => None,
+            pos => Some(&self.gates[pos as usize]),
+        }
+    }
+
+    fn queue(&mut self, id: u32) {
+        if !self.queued[id as usize] {
+            self.queued[id as usize] = true;
+            self.worklist.push(id);
+        }
+    }
+
+    /// Queues a gate along with its readers, which may simplify once the gate does
+    fn queue_with_readers(&mut self, id: u32) {
+        self.queue(id);
 
-        if let Some(Gate { id, .. }) = gates
-            .iter()
-            .find(|g| g.meta().is_none() && g.a == 0 && g.b == 0)
-        {
-            // simplify nand involving 0 to a NOT operation
-            // which can potentially be combined with others later
+        for i in 0..self.readers[id as usize].len() {
+            self.queue(self.readers[id as usize][i]);
+        }
+    }
 
-            let one = *id;
+    fn set_inputs(&mut self, id: u32, a: u32, b: u32) {
+        let pos = self.pos[id as usize] as usize;
+        let g = &mut self.gates[pos];
+        let (old_a, old_b) = (g.a, g.b);
 
-            for cur in gates.iter_mut() {
-                if cur.meta().is_none() && cur.b == one {
-                    // simplify nand(a, 1) -> nand(a, a)
-                    cur.b = cur.a;
-                }
+        if (old_a, old_b) == (a, b) {
+            return;
+        }
 
-                if cur.meta().is_none() && cur.a == one {
-                    // simplify nand(1, b) -> nand(b, b)
-                    cur.a = cur.b;
-                }
+        g.a = a;
+        g.b = b;
+
+        for old in [old_a, old_b] {
+            self.refs[old as usize] -= 1;
+
+            if self.refs[old as usize] == 0 {
+                self.queue(old);
             }
         }
 
-        for index in (0..gates.len()).rev() {
-            let cur = &gates[index];
+        // stale or repeated readers are skipped by `replace`
+        for new in [a, b] {
+            self.refs[new as usize] += 1;
+            self.readers[new as usize].push(id);
+        }
+
+        self.queue_with_readers(id);
+    }
 
-            if cur.is_io() {
-                // don't remove IO gates
+    /// Makes every reader of gate `old` read gate `new` instead, and removes `old`. Its names become
+    /// aliases of `new`.
+    fn replace(&mut self, old: u32, new: u32) {
+        for reader in std::mem::take(&mut self.readers[old as usize]) {
+            let Some(g) = self.gate(reader) else {
                 continue;
+            };
+
+            let a = if g.a == old { new } else { g.a };
+            let b = if g.b == old { new } else { g.b };
+            self.set_inputs(reader, a, b);
+        }
+
+        let old_pos = self.pos[old as usize] as usize;
+        let new_pos = self.pos[new as usize] as usize;
+
+        if let Some(meta) = self.gates[old_pos].meta.as_mut() {
+            for name in std::mem::take(&mut meta.names) {
+                let names = &mut self.gates[new_pos].add_meta().names;
+
+                if !names.contains(&name) {
+                    names.push(name);
+                }
             }
+        }
+
+        self.remove(old);
+    }
 
-            if !gates.iter().any(|o| o.a == cur.id || o.b == cur.id) {
-                // remove gate with unused output
+    fn remove(&mut self, id: u32) {
+        let pos = std::mem::replace(&mut self.pos[id as usize], NONE) as usize;
+        let g = &self.gates[pos];
 
-                remove_gate(gates, index, 0);
-                continue;
+        for input in [g.a, g.b] {
+            self.refs[input as usize] -= 1;
+
+            if self.refs[input as usize] == 0 {
+                self.queue(input);
             }
+        }
 
-            if let Some(o) = gates
-                .iter()
-                .find(|o|
-                    o.id < cur.id
-                    && (
-                        (o.a == cur.a && o.b == cur.b)
-                        || (o.a == cur.b && o.b == cur.a)
-                    )
-                    && !o.is_io())
-            {
-                // remove identical gate
-
-                let nid = o.id;
-                remove_gate(gates, index, nid);
-                continue;
+        // mark the slot dead, it is dropped at the end
+        self.gates[pos].id = NONE;
+    }
+
+    /// A gate that reads constant 0, which makes its output constant 1
+    fn is_one(&self, id: u32) -> bool {
+        self.gate(id).map(|g| is_plain(g) && g.a == 0 && g.b == 0).unwrap_or(false)
+    }
+
+    /// Applies every rule to a gate, which may remove it
+    fn simplify(&mut self, id: u32) {
+        let Some(cur) = self.gate(id) else {
+            return;
+        };
+
+        if cur.is_io() {
+            return;
+        }
+
+        let keep = self.keep_named && !cur.names().is_empty();
+
+        if self.refs[id as usize] == 0 && self.config.remove_dead && !keep {
+            // remove gate with unused output
+            self.remove(id);
+            self.report.dead_removed += 1;
+            return;
+        }
+
+        if is_plain(cur) && self.config.fold_constants {
+            let (mut a, mut b) = (cur.a, cur.b);
+
+            if a == 0 || b == 0 {
+                // simplify nand(a, 0), nand(0, b) -> nand(0, 0)
+                // which can be potentially be combined with others later
+                (a, b) = (0, 0);
+            } else {
+                // simplify nand(a, 1) -> nand(a, a) and nand(1, b) -> nand(b, b)
+                if self.is_one(b) {
+                    b = a;
+                }
+
+                if self.is_one(a) {
+                    a = b;
+                }
             }
 
-            if cur.a == cur.b && !cur.is_pinned() {
-                if let Some(Gate { a, .. }) = gates
-                    .iter()
-                    .find(|o|
-                        o.id < cur.id
-                        && cur.a == o.id
-                        && o.a == o.b
-                        && !o.is_io())
-                {
+            if (a, b) != (cur.a, cur.b) {
+                self.set_inputs(id, a, b);
+                self.report.constants_folded += 1;
+            }
+        }
+
+        if self.config.merge_duplicates && self.merge_duplicate(id) {
+            return;
+        }
+
+        let cur = self.gate(id).unwrap();
+
+        if cur.a == cur.b && !cur.is_pinned() && cur.delay() == 1 && self.config.remove_double_negations {
+            if let Some(o) = self.gate(cur.a) {
+                if o.id < id && o.a == o.b && !o.is_io() && o.delay() == 1 {
                     // simplify !!a -> a
+                    let a = o.a;
+                    self.replace(id, a);
+                    self.report.double_negations_removed += 1;
+                }
+            }
+        }
+    }
+
+    /// Merges a gate with an identical one, keeping the one with the lower ID. Returns true if the
+    /// gate itself was removed.
+    fn merge_duplicate(&mut self, id: u32) -> bool {
+        let cur = self.gate(id).unwrap();
+        let key = (cur.a.min(cur.b), cur.a.max(cur.b), cur.delay());
+
+        match self.structure.get(&key).copied() {
+            Some(other) if other != id && self.matches(other, key) => {
+                self.report.duplicates_merged += 1;
 
-                    let a = *a;
-                    remove_gate(gates, index, a);
-                    continue;
+                if other < id {
+                    self.replace(id, other);
+                    return true;
                 }
+
+                self.replace(other, id);
+                self.structure.insert(key, id);
+            }
+            _ => {
+                self.structure.insert(key, id);
             }
         }
 
-        if gates.len() == len_before {
-            break;
+        false
+    }
+
+    /// Whether a live non-IO gate still has the structure stored for it
+    fn matches(&self, id: u32, key: (u32, u32, u32)) -> bool {
+        self.gate(id)
+            .map(|g| !g.is_io() && (g.a.min(g.b), g.a.max(g.b), g.delay()) == key)
+            .unwrap_or(false)
+    }
+
+    fn run(&mut self) {
+        self.keep_named = true;
+        self.drain();
+
+        // unused named gates are kept until every gate had the chance to be merged into them, so that
+        // their names stay on an equivalent gate when there is one
+        self.keep_named = false;
+
+        for pos in 0..self.gates.len() {
+            let g = &self.gates[pos];
+
+            if g.id != NONE && self.refs[g.id as usize] == 0 && !g.names().is_empty() {
+                self.queue(g.id);
+            }
         }
+
+        self.drain();
     }
 
-    println!("pruned to {}", gates.len());
+    fn drain(&mut self) {
+        while let Some(id) = self.worklist.pop() {
+            self.queued[id as usize] = false;
+            self.simplify(id);
+        }
+    }
+}
+
+/// Whether the inputs of a gate may be rewritten. Names don't matter as they follow the gate.
+fn is_plain(g: &Gate) -> bool {
+    !g.is_io() && !g.is_pinned() && g.delay() == 1
 }
 
-fn remove_gate(
-    gates: &mut Vec<Gate>,
-    index: usize,
-    nid: u32)
-{
-    let oid = gates[index].id;
+pub fn optimize_gates(gates: &mut Vec<Gate>, config: &OptimizerConfig) -> OptimizerReport {
+    let mut graph = Graph::new(std::mem::take(gates), *config);
+    graph.run();
 
-    for gate in gates.iter_mut() {
-        if gate.id == oid {
-            gate.id = nid;
-        }
+    *gates = graph.gates;
+    gates.retain(|g| g.id != NONE);
+
+    OptimizerReport { gates_after: gates.len(), ..graph.report }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::simulator::*;
+    use crate::simulator::builder::GateBuilder;
+    use super::optimize_gates;
+
+    /// Builds gates without the folding that the builder does, to leave work for the optimizer
+    fn build_unfolded<R>(f: impl FnOnce() -> R) -> (R, Vec<Gate>) {
+        GateBuilder::default().optimizer(OptimizerConfig::none()).build_gates(f)
+    }
+
+    fn circuit() -> (Input, Input, Output) {
+        let (a_i, a) = input(1);
+        let (b_i, b) = input(1);
+        let (a, b) = (a.at(0), b.at(0));
 
-        if gate.a == oid {
-            gate.a = nid;
+        // duplicates, constants and double negations all fold into a single and
+        let mut x = nand(a, b);
+
+        for _ in 0..100 {
+            x = !!(x & nand(b, a) & one()) | zero();
         }
 
-        if gate.b == oid {
-            gate.b = nid;
+        (a_i, b_i, nand(x, x).output())
+    }
+
+    #[test]
+    fn test_optimize() {
+        let ((a_i, b_i, y), mut gates) = build_unfolded(circuit);
+        let built = gates.len();
+
+        let report = optimize_gates(&mut gates, &OptimizerConfig::default());
+
+        // constant 0, two inputs, nand(a, b) and the output
+        assert_eq!(gates.len(), 5);
+        assert_eq!(report.gates_before, built);
+        assert_eq!(report.gates_after, 5);
+        assert_eq!(
+            report.duplicates_merged + report.double_negations_removed + report.dead_removed,
+            built - 5);
+        assert!(report.constants_folded > 0);
+
+        let mut sim = SimpleSimulator::new(&gates);
+
+        for (a, b) in [(0u8, 0u8), (0, 1), (1, 0), (1, 1)] {
+            sim.set(&a_i, a);
+            sim.set(&b_i, b);
+            sim.step_until_settled(100).unwrap();
+            assert_eq!(sim.get::<u8>(&y), a & b);
         }
     }
 
-    gates.remove(index);
+    #[test]
+    fn test_optimizer_config() {
+        let (_, gates) = build_unfolded(circuit);
+
+        let (_, sim): (_, SimpleSimulator) = GateBuilder::default()
+            .optimizer(OptimizerConfig::none())
+            .build_simulator(circuit);
+
+        assert_eq!(sim.num_gates(), gates.len());
+        assert_eq!(sim.netlist().optimizer.gates_after, gates.len());
+
+        let only_dead = OptimizerConfig { remove_dead: true, ..OptimizerConfig::none() };
+        let report = optimize_gates(&mut gates.clone(), &only_dead);
+        assert_eq!(report.gates_before - report.gates_after, report.dead_removed);
+        assert_eq!(report.duplicates_merged + report.double_negations_removed + report.constants_folded, 0);
+
+        let no_dead = OptimizerConfig { remove_dead: false, ..OptimizerConfig::all() };
+        let report = optimize_gates(&mut gates.clone(), &no_dead);
+        assert_eq!(report.dead_removed, 0);
+        assert!(report.duplicates_merged > 0 && report.double_negations_removed > 0);
+    }
+
+    #[test]
+    fn test_names_follow_merges() {
+        let ((a_i, b_i), gates) = build_gates(|| {
+            let (a_i, a) = input(1);
+            let (b_i, b) = input(1);
+            let (a, b) = (a.at(0), b.at(0));
+
+            let x = nand(a, b).name("x");
+            nand(b, a).name("x dup");
+            let nx = (!x).name("not x");
+            (!nx).name("not not x");
+            (nx & one()).name("not x and one");
+            (nand(x, x) & a).output();
+
+            (a_i, b_i)
+        });
+
+        let (_, unnamed) = build_gates(|| {
+            let (_, a) = input(1);
+            let (_, b) = input(1);
+            let (a, b) = (a.at(0), b.at(0));
+
+            let x = nand(a, b);
+            (nand(x, x) & a).output();
+        });
+
+        let mut sim = SimpleSimulator::new(&gates);
+        assert_eq!(sim.num_gates(), SimpleSimulator::new(&unnamed).num_gates());
+
+        for (a, b) in [(0u8, 0u8), (0, 1), (1, 0), (1, 1)] {
+            sim.set(&a_i, a);
+            sim.set(&b_i, b);
+            sim.step_until_settled(100).unwrap();
+
+            let x = 1 - (a & b);
+            assert_eq!(sim.probe("x").unwrap(), x);
+            assert_eq!(sim.probe("x dup").unwrap(), x);
+            assert_eq!(sim.probe("not x").unwrap(), 1 - x);
+            assert_eq!(sim.probe("not not x").unwrap(), x);
+            assert_eq!(sim.probe("not x and one").unwrap(), 1 - x);
+        }
+    }
 }
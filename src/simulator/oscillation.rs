@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::simulator::netlist::{Fanout, Netlist};
+
+/// A feedback loop whose gates kept toggling when the circuit failed to settle
+#[derive(Clone, Debug)]
+pub struct Oscillation {
+    /// Number of gates in the loop
+    pub gates: usize,
+
+    /// Total number of times the gates in the loop toggled during the diagnosis
+    pub toggles: usize,
+
+    /// The named signals closest to the loop
+    pub signals: Vec<String>,
+
+    /// Number of gates between the loop and `signals`, or 0 if they are part of the loop
+    pub distance: usize,
+}
+
+impl fmt::Display for Oscillation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loop of {} gates toggled {} times", self.gates, self.toggles)?;
+
+        match (self.signals.is_empty(), self.distance) {
+            (true, _) => write!(f, ", no named signals nearby"),
+            (false, 0) => write!(f, ", contains {}", self.signals.join(", ")),
+            (false, d) => write!(f, ", {} gates from {}", d, self.signals.join(", ")),
+        }
+    }
+}
+
+/// Groups gates that toggled at least twice into strongly connected feedback loops, and finds the
+/// nearest named signals of each. `toggles` has the number of toggles for each gate. The result is
+/// ordered by the number of toggles, most active loop first.
+pub fn find_oscillations(netlist: &Netlist, toggles: &[u32]) -> Vec<Oscillation> {
+    let active = |index: u32| toggles[index as usize] >= 2;
+    let fanout = netlist.fanout();
+
+    let names = names_by_index(netlist);
+
+    let mut result: Vec<Oscillation> = strongly_connected(netlist.len(), |index| {
+        fanout[index as usize].iter().copied().filter(|&f| active(f))
+    }, active)
+        .into_iter()
+        .filter(|scc| {
+            // a single gate only forms a loop if it reads itself
+            let (a, b) = netlist.gates[scc[0] as usize];
+            scc.len() > 1 || a == scc[0] || b == scc[0]
+        })
+        .map(|scc| {
+            let (signals, distance) = nearest_names(netlist, &fanout, &names, &scc);
+
+            Oscillation {
+                gates: scc.len(),
+                toggles: scc.iter().map(|&index| toggles[index as usize] as usize).sum(),
+                signals,
+                distance,
+            }
+        })
+        .collect();
+
+    result.sort_by_key(|o| std::cmp::Reverse(o.toggles));
+    result
+}
+
+/// Names of each gate, indexed by gate
+pub fn names_by_index(netlist: &Netlist) -> Vec<Vec<String>> {
+    let mut names = vec![vec![]; netlist.len()];
+    for (index, name) in &netlist.names {
+        names[*index].push(name.clone());
+    }
+
+    names
+}
+
+/// Tarjan's algorithm over the gates for which `include` is true, without recursion
+fn strongly_connected<I: Iterator<Item=u32>>(
+    len: usize,
+    edges: impl Fn(u32) -> I,
+    include: impl Fn(u32) -> bool,
+) -> Vec<Vec<u32>> {
+    const NONE: u32 = u32::MAX;
+
+    let mut order = vec![NONE; len];
+    let mut low = vec![0u32; len];
+    let mut on_stack = vec![false; len];
+    let mut stack = Vec::new();
+    let mut next = 0;
+    let mut sccs = Vec::new();
+
+    for root in 0..len as u32 {
+        if !include(root) || order[root as usize] != NONE {
+            continue;
+        }
+
+        let mut path: Vec<(u32, Vec<u32>)> = Vec::new();
+
+        order[root as usize] = next;
+        low[root as usize] = next;
+        next += 1;
+        stack.push(root);
+        on_stack[root as usize] = true;
+        path.push((root, edges(root).collect()));
+
+        while let Some((index, pending)) = path.last_mut() {
+            let index = *index;
+
+            if let Some(to) = pending.pop() {
+                if order[to as usize] == NONE {
+                    order[to as usize] = next;
+                    low[to as usize] = next;
+                    next += 1;
+                    stack.push(to);
+                    on_stack[to as usize] = true;
+                    path.push((to, edges(to).collect()));
+                } else if on_stack[to as usize] {
+                    low[index as usize] = low[index as usize].min(order[to as usize]);
+                }
+
+                continue;
+            }
+
+            path.pop();
+
+            if let Some(&(parent, _)) = path.last() {
+                low[parent as usize] = low[parent as usize].min(low[index as usize]);
+            }
+
+            if low[index as usize] == order[index as usize] {
+                let mut scc = Vec::new();
+
+                loop {
+                    let g = stack.pop().unwrap();
+                    on_stack[g as usize] = false;
+                    scc.push(g);
+
+                    if g == index {
+                        break;
+                    }
+                }
+
+                scc.sort_unstable();
+                sccs.push(scc);
+            }
+        }
+    }
+
+    sccs
+}
+
+/// Breadth-first search from a group of gates towards both their inputs and their readers, until
+/// a distance with named gates is reached
+pub fn nearest_names(
+    netlist: &Netlist,
+    fanout: &Fanout,
+    names: &[Vec<String>],
+    start: &[u32],
+) -> (Vec<String>, usize) {
+    let mut distance = vec![usize::MAX; netlist.len()];
+    let mut queue: VecDeque<u32> = start.iter().copied().collect();
+
+    for &index in start {
+        distance[index as usize] = 0;
+    }
+
+    let mut found = Vec::new();
+    let mut found_distance = usize::MAX;
+
+    while let Some(index) = queue.pop_front() {
+        let d = distance[index as usize];
+
+        if d > found_distance {
+            break;
+        }
+
+        if !names[index as usize].is_empty() {
+            found.extend(names[index as usize].iter().cloned());
+            found_distance = d;
+            continue;
+        }
+
+        let (a, b) = netlist.gates[index as usize];
+        let inputs = if index as usize >= netlist.n_inputs { vec![a, b] } else { vec![] };
+
+        for next in inputs.into_iter().chain(fanout[index as usize].iter().copied()) {
+            if distance[next as usize] == usize::MAX {
+                distance[next as usize] = d + 1;
+                queue.push_back(next);
+            }
+        }
+    }
+
+    found.sort();
+    found.dedup();
+
+    (found, if found_distance == usize::MAX { 0 } else { found_distance })
+}
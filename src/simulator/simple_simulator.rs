@@ -1,78 +1,36 @@
-use std::cmp::{min, max};
-use std::collections::BTreeMap;
+use std::io;
 
 use rayon::prelude::*;
 
 use crate::simulator::*;
+use crate::simulator::netlist::Netlist;
+use crate::simulator::bits::Bits;
+use crate::simulator::state_file::{StateReader, StateWriter};
 
+/// Double-buffered simulator that evaluates every gate on each step.
 pub struct SimpleSimulator {
     cur_out: usize,
     state: [Vec<u8>; 2],
-    names: Vec<(usize, String, String)>,
-    input_map: BTreeMap<u32, usize>,
-    output_map: BTreeMap<u32, usize>,
-    n_inputs: usize,
-    gates: Vec<(u32, u32)>,
+    forced: Bits,
+    netlist: Netlist,
+    traces: Vec<String>,
+    clock: Clock,
 }
 
 impl Simulator for SimpleSimulator {
-    fn new(gates: &[Gate]) -> Self {
-        let mut gates = gates.to_vec();
-
-        super::optimizer::optimize_gates(&mut gates);
-
-        gates.sort_by_key(|g| (
-            std::cmp::Reverse(g.is_input()),
-            g.id,
-        ));
-
-        let index_map: BTreeMap<_, _> = gates
-            .iter()
-            .enumerate()
-            .map(|(index, g)| (g.id, index as u32))
-            .collect();
-
-        let n_inputs = gates
-            .iter()
-            .take_while(|g| g.is_input())
-            .count();
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
 
         SimpleSimulator {
             cur_out: 0,
             state: [
-                vec![0; gates.len()],
-                vec![0; gates.len()]
+                vec![0; netlist.len()],
+                vec![0; netlist.len()],
             ],
-            names: gates
-                .iter()
-                .filter_map(|g|
-                    g.meta()
-                        .and_then(|m| m.name.as_ref())
-                        .map(|n| (g.id, n.clone())))
-                .map(|(id, name)| (*index_map.get(&id).unwrap() as usize, name, String::new()))
-                .collect(),
-            input_map: gates
-                .iter()
-                .filter_map(|g|
-                    g.meta()
-                        .and_then(|m| m.input_id)
-                        .map(|ioid| (ioid, *index_map.get(&g.id).unwrap() as usize)))
-                .collect(),
-            output_map: gates
-                .iter()
-                .filter_map(|g|
-                        g.meta()
-                        .and_then(|m| m.output_id)
-                        .map(|ioid| (ioid, *index_map.get(&g.id).unwrap() as usize)))
-                .collect(),
-            n_inputs,
-            gates: gates
-                .into_iter()
-                .map(|g| (
-                    *index_map.get(&g.a).unwrap(),
-                    *index_map.get(&g.b).unwrap()
-                ))
-                .collect(),
+            forced: Bits::new(netlist.len()),
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            netlist,
         }
     }
 
@@ -80,7 +38,12 @@ impl Simulator for SimpleSimulator {
         let bits = bits.into();
 
         for (bit, id) in input.0.iter().copied().enumerate() {
-            let index = *self.input_map.get(&id).unwrap();
+            let index = self.netlist.input_index(id);
+
+            if self.forced.get(index) != 0 {
+                continue;
+            }
+
             let b = bits & (1 << bit) != 0;
             self.state[self.cur_out][index] = b as u8;
             self.state[1 - self.cur_out][index] = b as u8;
@@ -93,7 +56,7 @@ impl Simulator for SimpleSimulator {
         let mut r = 0u64;
 
         for (bit, id) in output.0.iter().copied().enumerate() {
-            let index= *self.output_map.get(&id).unwrap();
+            let index = self.netlist.output_index(id);
             r |= (self.state[self.cur_out][index] as u64) << bit;
         }
 
@@ -112,15 +75,22 @@ impl Simulator for SimpleSimulator {
         };
 
         let chunk_size = 256;
+        let n_inputs = self.netlist.n_inputs;
+        let gates = &self.netlist.gates;
+        let forced = &self.forced;
 
-        (&mut state_out[self.n_inputs..])
+        state_out[n_inputs..]
             .par_chunks_mut(chunk_size)
             .enumerate()
             .for_each(|(chunk_index, out)| {
-                let offset = chunk_index * chunk_size + self.n_inputs;
+                let offset = chunk_index * chunk_size + n_inputs;
 
                 for (index, out) in out.iter_mut().enumerate() {
-                    let g = &self.gates[index + offset];
+                    if forced.get(index + offset) != 0 {
+                        continue;
+                    }
+
+                    let g = &gates[index + offset];
                     *out = (state_in[g.0 as usize] & state_in[g.1 as usize]) ^ 0x01;
                 }
             });
@@ -144,24 +114,90 @@ impl Simulator for SimpleSimulator {
     }
 
     fn snapshot(&mut self) {
-        for (index, _, out) in &mut self.names {
-            let v = self.state[self.cur_out][*index as usize] != 0;
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            let v = self.state[self.cur_out][*index] != 0;
             out.push(if v { '█' } else { '▁' })
         }
     }
 
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        let mut w = StateWriter::new("SimpleSimulator", self.netlist.fingerprint());
+        w.u64(self.cur_out as u64);
+        w.u8s(&self.state[0]);
+        w.u8s(&self.state[1]);
+        w.bits(&self.forced);
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = self.netlist.len();
+
+        let mut r = StateReader::new(bytes, "SimpleSimulator", self.netlist.fingerprint())?;
+        let cur_out = (r.u64()? != 0) as usize;
+        let state = [r.values(len, 1)?, r.values(len, 1)?];
+        let forced = r.bits(len)?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        self.cur_out = cur_out;
+        self.state = state;
+        self.forced = forced;
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
     fn show(&self) {
-        let pad = self.names.iter().map(|(_, name, _)| name.len()).max().unwrap() + 1;
+        let pad = self.netlist.name_pad();
+
+        self.clock.show_ruler(pad);
 
-        for (_, name, out) in &self.names {
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
             println!("{name:pad$}{out}", name=name, pad=pad, out=out);
         }
 
         //println!("max steps: {}", self.max_steps);
-        println!("gates: {}", self.gates.len());
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
     }
 
     fn num_gates(&self) -> usize {
-        self.gates.len()
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.state[self.cur_out][index]
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        self.forced.set(index, value.is_some() as u8);
+
+        if let Some(val) = value {
+            self.state[0][index] = val;
+            self.state[1][index] = val;
+        }
     }
 }
@@ -1,5 +1,15 @@
+use std::io;
+use std::path::Path;
+
+use crate::simulator::clock::{Clock, Stop};
+use crate::simulator::netlist::Netlist;
+use crate::simulator::optimizer::OptimizerConfig;
+use crate::simulator::watch::{Signal, WatchError};
+
+#[derive(Clone, Debug)]
 pub struct Input(pub(super) Vec<u32>);
 
+#[derive(Clone, Debug)]
 pub struct Output(pub(super) Vec<u32>);
 
 #[derive(Clone, Debug)]
@@ -16,7 +26,7 @@ impl Gate {
     }
 
     pub fn add_meta(&mut self) -> &mut GateMeta {
-        self.meta.get_or_insert_with(|| Default::default())
+        self.meta.get_or_insert_with(Default::default)
     }
 
     pub fn is_input(&self) -> bool {
@@ -34,18 +44,34 @@ impl Gate {
     pub fn is_pinned(&self) -> bool {
         self.meta().map(|m| m.pinned).unwrap_or(false)
     }
+
+    pub fn delay(&self) -> u32 {
+        self.meta().and_then(|m| m.delay).unwrap_or(1)
+    }
+
+    pub fn names(&self) -> &[String] {
+        self.meta().map(|m| m.names.as_slice()).unwrap_or(&[])
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct GateMeta {
     pub pinned: bool,
-    pub name: Option<String>,
+    /// Names of the gate. A gate merged into another one by the optimizer adds its names to it.
+    pub names: Vec<String>,
     pub input_id: Option<u32>,
     pub output_id: Option<u32>,
+    /// Propagation delay in timesteps, used by `EventSimulator`. Defaults to 1.
+    pub delay: Option<u32>,
 }
 
 pub trait Simulator {
-    fn new(gates: &[Gate]) -> Self;
+    fn new(gates: &[Gate]) -> Self where Self: Sized {
+        Self::with_optimizer(gates, &OptimizerConfig::default())
+    }
+
+    /// Builds the simulator with the given optimizer settings instead of the default ones
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self;
 
     fn set(&mut self, input: &Input, bits: impl Into<u64>);
     fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
@@ -67,7 +93,264 @@ pub trait Simulator {
 
     fn snapshot(&mut self);
 
+    /// Saves the gate state, any pending work and the snapshot traces
+    fn save_state_bytes(&self) -> Vec<u8> {
+        self.save_state_bytes_with(true)
+    }
+
+    /// Like `save_state_bytes`, but leaves out the snapshot traces unless `traces` is set. Loading
+    /// such a state keeps the current traces.
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8>;
+
+    /// Restores a state saved with `save_state_bytes`. Fails with `ErrorKind::InvalidData`, leaving
+    /// the simulator unchanged, if the state was saved by a different backend or for a different
+    /// netlist.
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Number of snapshots taken
+    fn snapshots(&self) -> usize {
+        self.clock().snapshots()
+    }
+
+    /// Drops the snapshots after the first `snapshots` from the traces
+    fn truncate_snapshots(&mut self, snapshots: usize);
+
+    /// Saves the gate state, any pending work and the snapshot traces to a file
+    fn save_state(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.save_state_bytes())
+    }
+
+    /// Restores a state saved with `save_state`. Fails with `ErrorKind::InvalidData`, leaving the
+    /// simulator unchanged, if the file was saved by a different backend or for a different netlist.
+    fn load_state(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.load_state_bytes(&std::fs::read(path)?)
+    }
+
     fn show(&self);
 
     fn num_gates(&self) -> usize;
+
+    fn clock(&self) -> &Clock;
+
+    fn clock_mut(&mut self) -> &mut Clock;
+
+    fn netlist(&self) -> &Netlist;
+
+    /// Value of a gate by its index in the netlist
+    fn gate_value(&self, index: usize) -> u8;
+
+    /// Indices of the gates that may have changed in the last step, for backends that keep track of
+    /// them. Every other gate kept its value, apart from inputs that were set and gates that were
+    /// forced.
+    fn changed_gates(&self) -> Option<&[u32]> {
+        None
+    }
+
+    /// Holds a gate, by its index in the netlist, at a value regardless of its inputs, or releases
+    /// it if `value` is None. The change is propagated to the fan-out in the next step. A forced
+    /// input ignores `set`, and keeps the forced value after it is released until it is set again.
+    fn force_gate(&mut self, index: usize, value: Option<u8>);
+
+    /// Holds a named net, or each bit of a named bus, at a value regardless of its inputs until it is
+    /// released
+    fn force(&mut self, name: &str, value: u64) -> Result<(), WatchError> {
+        let signal = Signal::find(self.netlist(), name)?;
+
+        for (bit, &index) in signal.bits.iter().enumerate() {
+            self.force_gate(index, Some((value >> bit) as u8 & 1));
+        }
+
+        Ok(())
+    }
+
+    /// Releases a net or bus held by `force`. Gates are evaluated from their inputs again in the next
+    /// step, while inputs keep the forced value until they are `set`.
+    fn release(&mut self, name: &str) -> Result<(), WatchError> {
+        let signal = Signal::find(self.netlist(), name)?;
+
+        for &index in &signal.bits {
+            self.force_gate(index, None);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the current value of any gate named with `V::name`, whether or not it is an output.
+    /// Like `probe_bus`, a value that isn't 1, such as unknown, reads as 0.
+    fn probe(&self, name: &str) -> Result<u8, WatchError> {
+        let signal = Signal::net(self.netlist(), name)?;
+        Ok(signal.read(|index| self.gate_value(index)) as u8)
+    }
+
+    /// Reads the current value of a bus named with `VVec::name`, from its bits "name 0",
+    /// "name 1" etc. Bits that aren't 1, such as unknown values, read as 0.
+    fn probe_bus(&self, name: &str) -> Result<u64, WatchError> {
+        let signal = Signal::bus(self.netlist(), name)?;
+        Ok(signal.read(|index| self.gate_value(index)))
+    }
+
+    /// Registers an input as the clock for `clock_edge` and `run_cycles`. The clock starts low.
+    fn set_clock(&mut self, clk: &Input) {
+        self.set(clk, 0u8);
+
+        let clock = self.clock_mut();
+        clock.input = Some(clk.clone());
+        clock.level = 0;
+    }
+
+    /// Sets the maximum number of timesteps a clock edge may take to settle. Defaults to 1000.
+    fn set_settle_limit(&mut self, max_steps: usize) {
+        self.clock_mut().max_steps = max_steps;
+    }
+
+    /// Takes a snapshot after every clock edge in `clock_edge` and `run_cycles`
+    fn set_clock_trace(&mut self, trace: bool) {
+        self.clock_mut().trace = trace;
+    }
+
+    /// Number of full clock cycles run with `clock_edge` or `run_cycles`
+    fn cycles(&self) -> u64 {
+        self.clock().cycles
+    }
+
+    /// Toggles the clock and runs the simulation until it settles. A cycle is counted with each
+    /// falling edge.
+    fn clock_edge(&mut self) -> Result<(), Stop> {
+        self.clock_edge_with(|_| Ok(()))
+    }
+
+    /// Like `clock_edge`, but calls `check` after every timestep and stops as soon as it fails. The
+    /// edge is then left unsettled.
+    fn clock_edge_with(&mut self, mut check: impl FnMut(&Self) -> Result<(), Stop>) -> Result<(), Stop> {
+        let clock = self.clock();
+        let input = clock.input.clone().expect("no clock registered with set_clock");
+        let level = clock.level ^ 1;
+        let max_steps = clock.max_steps;
+
+        self.set(&input, level);
+        self.clock_mut().level = level;
+
+        if level == 0 {
+            self.clock_mut().cycles += 1;
+        }
+
+        let mut settled = false;
+
+        for _ in 0..max_steps {
+            settled = self.step_until_settled(1).is_some();
+            check(self)?;
+
+            if settled {
+                break;
+            }
+        }
+
+        if self.clock().trace {
+            self.snapshot();
+        }
+
+        if !settled {
+            return Err(Stop::NotSettled { cycle: self.cycles() });
+        }
+
+        Ok(())
+    }
+
+    /// Runs a number of full clock cycles, each a rising and a falling edge, letting the circuit
+    /// settle after each edge
+    fn run_cycles(&mut self, cycles: u64) -> Result<(), Stop> {
+        for _ in 0..cycles {
+            self.clock_edge()?;
+            self.clock_edge()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::testing::cpu_system;
+    use crate::simulator::*;
+
+    fn check_force<S: Simulator>() {
+        let (io, mut sim): (_, S) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+        sim.run_cycles(5).unwrap();
+
+        sim.force("pc", 2).unwrap();
+        for _ in 0..20 {
+            sim.run_cycles(1).unwrap();
+            assert_eq!(sim.probe_bus("pc").unwrap(), 2);
+        }
+
+        sim.release("pc").unwrap();
+        let mut pcs = Vec::new();
+        for _ in 0..20 {
+            sim.run_cycles(1).unwrap();
+            pcs.push(sim.probe_bus("pc").unwrap());
+        }
+        assert!(pcs.iter().any(|&pc| pc != 2));
+
+        sim.force("r0", 0x5a).unwrap();
+        let mut writes = 0;
+        for _ in 0..20 {
+            sim.run_cycles(1).unwrap();
+
+            if sim.get::<u8>(&io.w) == 1 {
+                assert_eq!(sim.get::<u8>(&io.data), 0x5a);
+                writes += 1;
+            }
+        }
+        assert!(writes > 0);
+
+        assert_eq!(sim.force("nope", 0), Err(WatchError::UnknownSignal("nope".into())));
+    }
+
+    #[test]
+    fn test_force() {
+        check_force::<ChangeListSimulator>();
+        check_force::<LevelizedSimulator>();
+        check_force::<EventSimulator>();
+        check_force::<SimpleSimulator>();
+        check_force::<BitParallelSimulator>();
+    }
+
+    fn check_save_without_traces<S: Simulator>() {
+        let (io, mut sim): (_, S) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+        sim.set_clock_trace(true);
+        sim.run_cycles(3).unwrap();
+
+        let state = sim.save_state_bytes();
+        let untraced = sim.save_state_bytes_with(false);
+        assert!(untraced.len() < state.len());
+        assert_eq!(sim.snapshots(), 6);
+
+        // loading leaves the traces alone, so they are cut back separately
+        sim.run_cycles(2).unwrap();
+        sim.load_state_bytes(&untraced).unwrap();
+        assert_eq!(sim.snapshots(), 10);
+
+        sim.truncate_snapshots(6);
+        assert_eq!(sim.save_state_bytes(), state);
+    }
+
+    #[test]
+    fn test_save_without_traces() {
+        check_save_without_traces::<ChangeListSimulator>();
+        check_save_without_traces::<LevelizedSimulator>();
+        check_save_without_traces::<EventSimulator>();
+        check_save_without_traces::<SimpleSimulator>();
+        check_save_without_traces::<BitParallelSimulator>();
+        check_save_without_traces::<TernarySimulator>();
+    }
 }
@@ -0,0 +1,274 @@
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+use crate::simulator::bits::{word_mask, Bits};
+
+const MAGIC: &[u8; 8] = b"NANDSIM\0";
+const VERSION: u32 = 5;
+
+/// Builds a saved simulator state.
+///
+/// A saved state starts with a header naming the backend that wrote it and the fingerprint of its
+/// netlist, followed by the backend's fields as little-endian, length-prefixed arrays.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(backend: &str, fingerprint: u64) -> Self {
+        let mut w = StateWriter { buf: Vec::new() };
+        w.buf.extend_from_slice(MAGIC);
+        w.u32(VERSION);
+        w.str(backend);
+        w.u64(fingerprint);
+        w
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u8s(&mut self, v: &[u8]) {
+        self.u64(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn u32s(&mut self, v: &[u32]) {
+        self.u64(v.len() as u64);
+        for &x in v {
+            self.u32(x);
+        }
+    }
+
+    pub fn u64s(&mut self, v: &[u64]) {
+        self.u64(v.len() as u64);
+        for &x in v {
+            self.u64(x);
+        }
+    }
+
+    pub fn bits(&mut self, v: &Bits) {
+        self.u64s(v.words());
+    }
+
+    pub fn str(&mut self, v: &str) {
+        self.u8s(v.as_bytes());
+    }
+
+    pub fn strs(&mut self, v: &[String]) {
+        self.u64(v.len() as u64);
+        for s in v {
+            self.str(s);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back a state built by `StateWriter`, failing with `ErrorKind::InvalidData` if it is
+/// malformed or was saved from a different backend or netlist.
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8], backend: &str, fingerprint: u64) -> Result<Self> {
+        let mut r = StateReader {
+            buf,
+            pos: 0,
+        };
+
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a simulator state file".into()));
+        }
+
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported state file version {}", version)));
+        }
+
+        let saved_backend = r.str()?;
+        if saved_backend != backend {
+            return Err(invalid(format!("state was saved by {}, not {}", saved_backend, backend)));
+        }
+
+        let saved_fingerprint = r.u64()?;
+        if saved_fingerprint != fingerprint {
+            return Err(invalid(format!(
+                "state was saved for a different netlist (fingerprint {:016x}, expected {:016x})",
+                saved_fingerprint,
+                fingerprint)));
+        }
+
+        Ok(r)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("state file is truncated".into()));
+        }
+
+        self.pos += n;
+        Ok(&self.buf[self.pos - n..self.pos])
+    }
+
+    fn len(&mut self, size: usize) -> Result<usize> {
+        let len = self.u64()? as usize;
+
+        if len.saturating_mul(size) > self.buf.len() - self.pos {
+            return Err(invalid("state file is truncated".into()));
+        }
+
+        Ok(len)
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn u8s(&mut self) -> Result<Vec<u8>> {
+        let len = self.len(1)?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn u32s(&mut self) -> Result<Vec<u32>> {
+        let len = self.len(4)?;
+        (0..len).map(|_| self.u32()).collect()
+    }
+
+    pub fn u64s(&mut self) -> Result<Vec<u64>> {
+        let len = self.len(8)?;
+        (0..len).map(|_| self.u64()).collect()
+    }
+
+    /// Reads a bit vector that must have `len` bits
+    pub fn bits(&mut self, len: usize) -> Result<Bits> {
+        let mut bits = Bits::new(len);
+        let words = self.u64s()?;
+
+        if words.len() != bits.words().len() {
+            return Err(invalid("gate state has the wrong length".into()));
+        }
+
+        if words.last().is_some_and(|&w| w & !word_mask(words.len() - 1, 0, len) != 0) {
+            return Err(invalid("gate state has bits past the last gate".into()));
+        }
+
+        bits.words_mut().copy_from_slice(&words);
+        Ok(bits)
+    }
+
+    /// Reads an array that must have `len` entries
+    pub fn u8s_len(&mut self, len: usize) -> Result<Vec<u8>> {
+        check_len(self.u8s()?, len)
+    }
+
+    /// Reads an array that must have `len` entries
+    pub fn u32s_len(&mut self, len: usize) -> Result<Vec<u32>> {
+        check_len(self.u32s()?, len)
+    }
+
+    /// Reads an array that must have `len` entries
+    pub fn u64s_len(&mut self, len: usize) -> Result<Vec<u64>> {
+        check_len(self.u64s()?, len)
+    }
+
+    /// Reads gate values that must have `len` entries, each at most `max`
+    pub fn values(&mut self, len: usize, max: u8) -> Result<Vec<u8>> {
+        let v = self.u8s_len(len)?;
+
+        if v.iter().any(|&x| x > max) {
+            return Err(invalid("gate value out of range".into()));
+        }
+
+        Ok(v)
+    }
+
+    /// Reads gate indices that must all be in `range`, such as the non-input gates of a change list
+    pub fn indices(&mut self, range: Range<usize>) -> Result<Vec<u32>> {
+        let v = self.u32s()?;
+
+        if v.iter().any(|&i| !range.contains(&(i as usize))) {
+            return Err(invalid("gate index out of range".into()));
+        }
+
+        Ok(v)
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        String::from_utf8(self.u8s()?).map_err(|_| invalid("invalid string".into()))
+    }
+
+    /// Reads strings, such as the traces of the named signals, that must have `len` entries
+    pub fn strs(&mut self, len: usize) -> Result<Vec<String>> {
+        let n = self.len(8)?;
+        check_len((0..n).map(|_| self.str()).collect::<Result<_>>()?, len)
+    }
+
+    /// Checks that the whole state was read
+    pub fn finish(self) -> Result<()> {
+        if self.pos != self.buf.len() {
+            return Err(invalid("unexpected data at end of state file".into()));
+        }
+
+        Ok(())
+    }
+}
+
+fn check_len<T>(v: Vec<T>, len: usize) -> Result<Vec<T>> {
+    if v.len() != len {
+        return Err(invalid("saved state has the wrong length".into()));
+    }
+
+    Ok(v)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use super::{StateReader, StateWriter};
+
+    #[test]
+    fn test_values_out_of_range() {
+        let mut w = StateWriter::new("Test", 1);
+        w.u8s(&[0, 1, 1]);
+        w.u8s(&[0, 2, 1]);
+
+        let bytes = w.finish();
+        let mut r = StateReader::new(&bytes, "Test", 1).unwrap();
+
+        assert_eq!(r.values(3, 1).unwrap(), [0, 1, 1]);
+        assert_eq!(r.values(3, 1).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_indices_out_of_range() {
+        let mut w = StateWriter::new("Test", 1);
+        w.u32s(&[2, 4, 3]);
+        w.u32s(&[2, 1]);
+        w.u32s(&[5]);
+
+        let bytes = w.finish();
+        let mut r = StateReader::new(&bytes, "Test", 1).unwrap();
+
+        assert_eq!(r.indices(2..5).unwrap(), [2, 4, 3]);
+        assert_eq!(r.indices(2..5).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(r.indices(2..5).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,412 @@
+use std::io;
+use std::mem::swap;
+
+use crate::simulator::*;
+use crate::simulator::netlist::{Fanout, Netlist};
+use crate::simulator::bits::Bits;
+use crate::simulator::state_file::{StateReader, StateWriter};
+
+/// Unknown logic value
+pub const X: u8 = 2;
+
+/// Change list simulator with a third, unknown value X.
+///
+/// Every gate and input starts out as X, apart from the constant zero. A NAND with a 0 input is 1
+/// even if its other input is X, so known values resolve as far as the logic allows, while state
+/// that is never reset stays X. This makes a register without a working reset stand out, where the
+/// two-valued simulators would silently start it at 0.
+///
+/// `get` panics if an output has X bits. Use `get_known` or `get_bits` to read outputs that may be
+/// unknown, or `get_x_as_zero` to read X bits as 0 the way `probe` and `probe_bus` do. `probe_bits`
+/// tells unknown bits of named nets apart.
+pub struct TernarySimulator {
+    state: Vec<u8>,
+    forced: Bits,
+    change_list: Vec<u32>,
+    new_change_list: Vec<u32>,
+    netlist: Netlist,
+    traces: Vec<String>,
+    clock: Clock,
+    fanout: Fanout,
+}
+
+fn nand3(a: u8, b: u8) -> u8 {
+    match (a, b) {
+        (0, _) | (_, 0) => 1,
+        (1, 1) => 0,
+        _ => X,
+    }
+}
+
+/// Formats values from the least significant bit up as `0`, `1` and `X`, most significant first
+fn bits_string(values: impl DoubleEndedIterator<Item=u8>) -> String {
+    values
+        .rev()
+        .map(|v| match v {
+            0 => '0',
+            1 => '1',
+            _ => 'X',
+        })
+        .collect()
+}
+
+impl TernarySimulator {
+    /// Sets an input back to X
+    pub fn set_unknown(&mut self, input: &Input) {
+        for id in input.0.iter().copied() {
+            let index = self.netlist.input_index(id);
+
+            if self.forced.get(index) != 0 {
+                continue;
+            }
+
+            self.state[index] = X;
+            self.change_list.extend_from_slice(&self.fanout[index]);
+        }
+    }
+
+    /// Reads an output, or returns None if any of its bits is X
+    pub fn get_known<R: TryFrom<u64>>(&self, output: &Output) -> Option<R>
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        let mut r = 0u64;
+
+        for (bit, id) in output.0.iter().copied().enumerate() {
+            match self.state[self.netlist.output_index(id)] {
+                X => return None,
+                v => r |= (v as u64) << bit,
+            }
+        }
+
+        Some(r.try_into().expect("output too long for data type"))
+    }
+
+    /// Reads an output with its X bits as 0, like the two-valued simulators would start them
+    pub fn get_x_as_zero<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        let mut r = 0u64;
+
+        for (bit, id) in output.0.iter().copied().enumerate() {
+            r |= ((self.state[self.netlist.output_index(id)] == 1) as u64) << bit;
+        }
+
+        r.try_into().expect("output too long for data type")
+    }
+
+    /// Reads an output as a string of `0`, `1` and `X`, most significant bit first
+    pub fn get_bits(&self, output: &Output) -> String {
+        bits_string(output.0.iter().map(|&id| self.state[self.netlist.output_index(id)]))
+    }
+
+    /// Reads a named net or bus as a string of `0`, `1` and `X`, most significant bit first
+    pub fn probe_bits(&self, name: &str) -> Result<String, WatchError> {
+        let signal = Signal::find(&self.netlist, name)?;
+        Ok(bits_string(signal.bits.iter().map(|&index| self.state[index])))
+    }
+
+    /// Number of gates whose value is X
+    pub fn num_unknown(&self) -> usize {
+        self.state.iter().filter(|&&v| v == X).count()
+    }
+}
+
+impl Simulator for TernarySimulator {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        let netlist = Netlist::new(gates, config);
+
+        let mut state = vec![X; netlist.len()];
+
+        // constant zero
+        state[0] = 0;
+
+        TernarySimulator {
+            state,
+            forced: Bits::new(netlist.len()),
+            change_list: (netlist.n_inputs as u32..netlist.len() as u32).collect(),
+            new_change_list: vec![],
+            traces: vec![String::new(); netlist.names.len()],
+            clock: Clock::default(),
+            fanout: netlist.fanout(),
+            netlist,
+        }
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        let bits = bits.into();
+
+        for (bit, id) in input.0.iter().copied().enumerate() {
+            let index = self.netlist.input_index(id);
+            let b = (bits & (1 << bit) != 0) as u8;
+
+            if self.state[index] != b && self.forced.get(index) == 0 {
+                self.state[index] = b;
+                self.change_list.extend_from_slice(&self.fanout[index]);
+            }
+        }
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        self.get_known(output)
+            .unwrap_or_else(|| panic!("output has unknown bits: {}", self.get_bits(output)))
+    }
+
+    /// Runs the simulation for one timestep
+    fn step(&mut self) {
+        self.new_change_list.clear();
+
+        for index in self.change_list.iter().copied() {
+            if self.forced.get(index as usize) != 0 {
+                continue;
+            }
+
+            let g = &self.netlist.gates[index as usize];
+
+            let val = nand3(self.state[g.0 as usize], self.state[g.1 as usize]);
+
+            if val != self.state[index as usize] {
+                self.state[index as usize] = val;
+                self.new_change_list.extend_from_slice(&self.fanout[index as usize]);
+            }
+        }
+
+        swap(&mut self.change_list, &mut self.new_change_list);
+    }
+
+    /// Runs the simulation until it settles or a maximum number of timesteps. Returns the number of
+    /// steps if the simulation settled within the allotted number of steps, or None if it didn't.
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        let mut i = 0;
+        while i < max_steps {
+            i += 1;
+
+            self.step();
+
+            if self.change_list.is_empty() {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.clock.snapshot();
+
+        for ((index, _), out) in self.netlist.names.iter().zip(self.traces.iter_mut()) {
+            out.push(match self.state[*index] {
+                0 => '▁',
+                1 => '█',
+                _ => '▒',
+            })
+        }
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        let mut w = StateWriter::new("TernarySimulator", self.netlist.fingerprint());
+        w.u8s(&self.state);
+        w.bits(&self.forced);
+        w.u32s(&self.change_list);
+        self.clock.save(&mut w, traces.then_some(self.traces.as_slice()));
+        w.finish()
+    }
+
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let len = self.netlist.len();
+
+        let mut r = StateReader::new(bytes, "TernarySimulator", self.netlist.fingerprint())?;
+        let state = r.values(len, X)?;
+        let forced = r.bits(len)?;
+        let change_list = r.indices(self.netlist.n_inputs..len)?;
+        let (clock, traces) = self.clock.load(&mut r, self.traces.len())?;
+        r.finish()?;
+
+        self.state = state;
+        self.forced = forced;
+        self.change_list = change_list;
+        self.clock = clock;
+
+        if let Some(traces) = traces {
+            self.traces = traces;
+        }
+
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.clock.truncate_snapshots(snapshots, &mut self.traces);
+    }
+
+    fn show(&self) {
+        let pad = self.netlist.name_pad();
+
+        self.clock.show_ruler(pad);
+
+        for ((_, name), out) in self.netlist.names.iter().zip(self.traces.iter()) {
+            println!("{name:pad$}{out}", name=name, pad=pad, out=out);
+        }
+
+        println!("gates: {}", self.netlist.len());
+        self.clock.show_cycles();
+        println!("unknown: {}", self.num_unknown());
+    }
+
+    fn num_gates(&self) -> usize {
+        self.netlist.len()
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    fn netlist(&self) -> &Netlist {
+        &self.netlist
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.state[index]
+    }
+
+    /// Forces a gate to 0, 1 or `X`
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        match value {
+            Some(val) => {
+                self.forced.set(index, 1);
+
+                if self.state[index] != val {
+                    self.state[index] = val;
+                    self.change_list.extend_from_slice(&self.fanout[index]);
+                }
+            }
+            None => {
+                self.forced.set(index, 0);
+
+                if index >= self.netlist.n_inputs {
+                    self.change_list.push(index as u32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::modules::*;
+    use crate::modules::testing::cpu_system;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_unreset_latch_stays_unknown() {
+        let ((clk_i, rst_i, data_i, with_reset, without_reset), mut sim): (_, TernarySimulator) = build_simulator(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let (data_i, data) = input(4);
+
+            // only loaded when the data is 0xf, which it never is below
+            let load = data.andv();
+
+            (
+                clk_i,
+                rst_i,
+                data_i,
+                latch(data, load, clk.at(0), !rst.at(0)).output(),
+                latch(data, load, clk.at(0), one()).output(),
+            )
+        });
+
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get_bits(&with_reset), "XXXX");
+
+        sim.set(&clk_i, 0u8);
+        sim.set(&data_i, 0x5u8);
+        sim.set(&rst_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        sim.set(&rst_i, 0u8);
+        sim.step_until_settled(100).unwrap();
+
+        for data in [0x5u8, 0xa, 0x3] {
+            sim.set(&data_i, data);
+            sim.set(&clk_i, 1u8);
+            sim.step_until_settled(100).unwrap();
+            sim.set(&clk_i, 0u8);
+            sim.step_until_settled(100).unwrap();
+        }
+
+        assert_eq!(sim.get::<u8>(&with_reset), 0);
+        assert_eq!(sim.get_known::<u8>(&without_reset), None);
+        assert_eq!(sim.get_bits(&without_reset), "XXXX");
+
+        sim.set(&data_i, 0xfu8);
+        sim.set(&clk_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get::<u8>(&without_reset), 0xf);
+    }
+
+    #[test]
+    #[should_panic(expected = "output has unknown bits: 1X")]
+    fn test_get_unknown() {
+        let (y, mut sim): (_, TernarySimulator) = build_simulator(|| {
+            let (_, a) = input(1);
+            [a.at(0), one()].into_iter().vv().output()
+        });
+
+        sim.step_until_settled(100).unwrap();
+        sim.get::<u8>(&y);
+    }
+
+    #[test]
+    fn test_cpu_reset_is_complete() {
+        let (io, gates) = build_gates(cpu_system);
+        let mut sim = TernarySimulator::new(&gates);
+
+        sim.set(&io.clk, 0u8);
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+
+        assert_eq!(sim.num_unknown(), 0);
+    }
+
+    #[test]
+    fn test_force() {
+        let ((a_i, b_i, y), mut sim): (_, TernarySimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (b_i, b) = input(1);
+
+            let n = nand(a.at(0), b.at(0)).name("n");
+            (a_i, b_i, nand(n, b.at(0)).output())
+        });
+
+        sim.set(&b_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get_bits(&y), "X");
+
+        // unknown bits only read as 0 when asked to
+        assert_eq!(sim.get_x_as_zero::<u8>(&y), 0);
+        assert_eq!(sim.get_known::<u8>(&y), None);
+        assert_eq!(sim.probe("n"), Ok(0));
+        assert_eq!(sim.probe_bits("n"), Ok("X".into()));
+
+        sim.force("n", 0).unwrap();
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get::<u8>(&y), 1);
+
+        sim.release("n").unwrap();
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get_bits(&y), "X");
+
+        sim.set(&a_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get::<u8>(&y), 1);
+
+        let n = sim.netlist().names.iter().find(|(_, name)| name == "n").unwrap().0;
+        sim.force_gate(n, Some(super::X));
+        sim.step_until_settled(100).unwrap();
+        assert_eq!(sim.get_bits(&y), "X");
+    }
+}
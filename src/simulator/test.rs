@@ -19,3 +19,21 @@ pub fn bench<S: Simulator>(sim: &mut S, clk: Input) {
     println!("elapsed: {}µs", elapsed_us);
     println!("clocks/s: {}k", kclocks_per_s);
 }
+
+/// Like `bench`, but runs each half clock until the circuit settles instead of a fixed number of
+/// steps. This is a fairer comparison for backends that do more work per step.
+pub fn bench_settled<S: Simulator>(sim: &mut S, clk: Input) {
+    let clocks: u64 = 100_000;
+
+    sim.set_clock(&clk);
+    sim.set_settle_limit(1000);
+
+    let start = SystemTime::now();
+    sim.run_cycles(clocks).expect("clock did not settle");
+    let end = SystemTime::now();
+
+    let elapsed_us = end.duration_since(start).unwrap().as_micros() as u64;
+    let kclocks_per_s = clocks * 1_000 / elapsed_us;
+    println!("elapsed: {}µs", elapsed_us);
+    println!("clocks/s: {}k", kclocks_per_s);
+}
@@ -4,7 +4,8 @@ use std::ops::Shl;
 use super::simulator::{Input, Output};
 use super::builder::GateBuilder;
 
-pub use super::builder::{V, VVec};
+pub use super::builder::{Bus, V, VVec};
+pub use super::bus::{BusFault, BusMonitor};
 
 thread_local! {
     static BUILDER: RefCell<Option<GateBuilder>> = Default::default();
@@ -46,6 +47,12 @@ impl V {
         builder(|gb| gb.pin(self));
         self
     }
+
+    /// Sets the propagation delay of the gate driving this value
+    pub fn delay(self, delay: u32) -> Self {
+        builder(|gb| gb.delay(self, delay));
+        self
+    }
 }
 
 impl VVec {
@@ -61,6 +68,10 @@ impl VVec {
         builder(|gb| gb.vv_len(self))
     }
 
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
     pub fn at(self, index: usize) -> V {
         builder(|gb| gb.vv_get(self)[index])
     }
@@ -82,6 +93,32 @@ impl VVec {
     }
 }
 
+impl Bus {
+    /// The value on the bus, which is the OR of all enabled drivers
+    pub fn value(self) -> VVec {
+        builder(|gb| gb.bus_value(self))
+    }
+
+    /// Attaches a driver that puts `data` on the bus while `enable` is high
+    pub fn drive(self, name: &str, data: VVec, enable: V) -> Self {
+        builder(|gb| gb.bus_drive(self, name, data, enable));
+        self
+    }
+
+    /// Registers a reader, so that the bus is only reported as floating while `enable` is high
+    pub fn read(self, name: &str, enable: V) -> Self {
+        builder(|gb| gb.bus_read(self, name, enable));
+        self
+    }
+
+    /// Returns a monitor for checking the bus for contention and floating reads, either directly or
+    /// after every clock edge with `Watched::watch_bus`. Call this after all drivers and readers have
+    /// been attached.
+    pub fn monitor(self) -> BusMonitor {
+        builder(|gb| gb.bus_monitor(self))
+    }
+}
+
 impl FromIterator<V> for VVec {
     fn from_iter<I: IntoIterator<Item=V>>(iter: I) -> Self {
         let vs = iter.into_iter().collect();
@@ -132,6 +169,12 @@ pub fn vv(size: usize) -> VVec {
     builder(|c| c.vv(size))
 }
 
+/// Creates a tri-state bus. Drivers attach to it with an output enable, and the bus can be
+/// checked for conflicting or missing drivers with a `BusMonitor`.
+pub fn bus(name: &str, bits: usize) -> Bus {
+    builder(|c| c.bus(name, bits))
+}
+
 pub fn zero() -> V {
     GateBuilder::zero()
 }
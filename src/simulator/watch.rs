@@ -0,0 +1,675 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+
+use crate::simulator::netlist::Netlist;
+use crate::simulator::{BusFault, BusMonitor, Clock, Gate, Input, OptimizerConfig, Output, Simulator, Stop};
+use crate::simulator::bus::BusSignals;
+
+/// A named signal, either a single gate or a bus named with `VVec::name`
+#[derive(Clone, Debug)]
+pub struct Signal {
+    pub name: String,
+
+    /// Netlist index of each bit, least significant first
+    pub bits: Vec<usize>,
+}
+
+impl Signal {
+    /// Finds a gate with exactly the given name, or else the bits of a bus with the name
+    pub fn find(netlist: &Netlist, name: &str) -> Result<Signal, WatchError> {
+        Signal::net(netlist, name).or_else(|_| Signal::bus(netlist, name))
+    }
+
+    /// Finds the gate with exactly the given name
+    pub fn net(netlist: &Netlist, name: &str) -> Result<Signal, WatchError> {
+        let (index, _) = netlist.names
+            .iter()
+            .find(|(_, n)| n == name)
+            .ok_or_else(|| WatchError::UnknownSignal(name.to_owned()))?;
+
+        Ok(Signal { name: name.to_owned(), bits: vec![*index] })
+    }
+
+    /// Groups the bits named "name 0", "name 1" etc. by `VVec::name` back into a bus
+    pub fn bus(netlist: &Netlist, name: &str) -> Result<Signal, WatchError> {
+        let mut bits: Vec<(usize, usize)> = netlist.names
+            .iter()
+            .filter_map(|(index, n)| {
+                let bit = n.strip_prefix(name)?.strip_prefix(' ')?.parse().ok()?;
+                Some((bit, *index))
+            })
+            .collect();
+
+        bits.sort_unstable();
+
+        // a bus with bits optimized away can't be read as a number
+        if bits.is_empty() || bits.iter().enumerate().any(|(i, &(bit, _))| i != bit) {
+            return Err(WatchError::UnknownSignal(name.to_owned()));
+        }
+
+        if bits.len() > 64 {
+            return Err(WatchError::TooWide(name.to_owned(), bits.len()));
+        }
+
+        Ok(Signal {
+            name: name.to_owned(),
+            bits: bits.into_iter().map(|(_, index)| index).collect(),
+        })
+    }
+
+    /// Reads the value of the signal. Bits that aren't 1, such as unknown values, read as 0.
+    pub fn read(&self, gate_value: impl Fn(usize) -> u8) -> u64 {
+        self.bits
+            .iter()
+            .enumerate()
+            .fold(0, |v, (i, &index)| v | ((gate_value(index) == 1) as u64) << i)
+    }
+}
+
+/// When a breakpoint fires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// The signal changes to the value
+    Equals(u64),
+
+    /// The signal changes from the value to something else
+    NotEquals(u64),
+
+    /// The signal changes from zero to non-zero
+    Rises,
+
+    /// The signal changes from non-zero to zero
+    Falls,
+
+    /// The signal changes at all
+    Changes,
+}
+
+impl Condition {
+    fn fires(&self, old: u64, new: u64) -> bool {
+        old != new && match *self {
+            Condition::Equals(v) => new == v,
+            Condition::NotEquals(v) => old == v,
+            Condition::Rises => old == 0,
+            Condition::Falls => new == 0,
+            Condition::Changes => true,
+        }
+    }
+}
+
+/// Error from parsing a breakpoint or looking up a named signal
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchError {
+    UnknownSignal(String),
+    /// A bus with more bits than fit in a u64
+    TooWide(String, usize),
+    Syntax(String),
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchError::UnknownSignal(name) => write!(f, "no signal or bus named {:?}", name),
+            WatchError::TooWide(name, bits) => write!(f, "bus {:?} has {} bits, more than 64", name, bits),
+            WatchError::Syntax(text) => write!(
+                f,
+                "can't parse breakpoint {:?}, expected \"<signal> == <value>\", \"<signal> != <value>\" or \
+                \"<signal> rises|falls|changes\"",
+                text),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+struct Breakpoint {
+    id: usize,
+    text: String,
+    signal: Signal,
+    condition: Condition,
+    last: u64,
+}
+
+struct Watcher {
+    id: usize,
+    signal: Signal,
+    last: u64,
+    f: Box<dyn FnMut(u64)>,
+}
+
+/// Breakpoints, watch closures and watched buses on the signals of a netlist
+#[derive(Default)]
+pub struct Watches {
+    next_id: usize,
+    breakpoints: Vec<Breakpoint>,
+    watchers: Vec<Watcher>,
+    buses: Vec<(usize, BusSignals)>,
+}
+
+impl Watches {
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchers.is_empty() && self.buses.is_empty()
+    }
+
+    /// Parses a breakpoint such as "pc == 0x0e" or "spi_cs falls". The signal is read right away
+    /// with `gate_value`, so that the breakpoint only fires on a later change.
+    pub fn add_breakpoint(
+        &mut self,
+        netlist: &Netlist,
+        text: &str,
+        gate_value: impl Fn(usize) -> u8,
+    ) -> Result<usize, WatchError> {
+        let syntax = || WatchError::Syntax(text.to_owned());
+
+        let (name, condition) = if let Some((name, value)) = text.split_once("==") {
+            (name, Condition::Equals(parse_value(value).ok_or_else(syntax)?))
+        } else if let Some((name, value)) = text.split_once("!=") {
+            (name, Condition::NotEquals(parse_value(value).ok_or_else(syntax)?))
+        } else {
+            let (name, edge) = text.trim().rsplit_once(' ').ok_or_else(syntax)?;
+
+            (name, match edge {
+                "rises" => Condition::Rises,
+                "falls" => Condition::Falls,
+                "changes" => Condition::Changes,
+                _ => return Err(syntax()),
+            })
+        };
+
+        let signal = Signal::find(netlist, name.trim())?;
+        let id = self.next_id();
+
+        self.breakpoints.push(Breakpoint {
+            id,
+            text: text.trim().to_owned(),
+            last: signal.read(gate_value),
+            signal,
+            condition,
+        });
+
+        Ok(id)
+    }
+
+    pub fn add_watcher(
+        &mut self,
+        netlist: &Netlist,
+        name: &str,
+        gate_value: impl Fn(usize) -> u8,
+        f: Box<dyn FnMut(u64)>,
+    ) -> Result<usize, WatchError> {
+        let signal = Signal::find(netlist, name)?;
+        let id = self.next_id();
+
+        self.watchers.push(Watcher {
+            id,
+            last: signal.read(gate_value),
+            signal,
+            f,
+        });
+
+        Ok(id)
+    }
+
+    /// Watches a bus for contention and floating reads with `check_buses`
+    pub fn add_bus(&mut self, netlist: &Netlist, monitor: &BusMonitor) -> Result<usize, WatchError> {
+        let signals = monitor.signals(netlist)?;
+        let id = self.next_id();
+
+        self.buses.push((id, signals));
+        Ok(id)
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Removes a breakpoint, watcher or watched bus. Returns false if there was none with the ID.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len() + self.watchers.len() + self.buses.len();
+
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchers.retain(|w| w.id != id);
+        self.buses.retain(|(bus, _)| *bus != id);
+
+        self.breakpoints.len() + self.watchers.len() + self.buses.len() != len
+    }
+
+    /// Runs the watchers of signals that changed, and returns every breakpoint that fired, as its ID,
+    /// text and the new value of its signal
+    pub fn check(&mut self, gate_value: impl Fn(usize) -> u8) -> Vec<(usize, String, u64)> {
+        for w in &mut self.watchers {
+            let value = w.signal.read(&gate_value);
+
+            if value != w.last {
+                w.last = value;
+                (w.f)(value);
+            }
+        }
+
+        let mut fired = Vec::new();
+
+        for b in &mut self.breakpoints {
+            let value = b.signal.read(&gate_value);
+
+            if b.condition.fires(b.last, value) {
+                fired.push((b.id, b.text.clone(), value));
+            }
+
+            b.last = value;
+        }
+
+        fired
+    }
+
+    /// Returns the first watched bus that is contended or floating, as its ID and the fault. Buses
+    /// are only meaningful once the circuit has settled, since drivers briefly overlap while their
+    /// enables change.
+    pub fn check_buses(&self, gate_value: impl Fn(usize) -> u8) -> Option<(usize, BusFault)> {
+        self.buses
+            .iter()
+            .find_map(|(id, signals)| Some((*id, signals.check(&gate_value)?)))
+    }
+}
+
+/// A simulator with breakpoints and watch closures on named signals, and watched buses.
+///
+/// `Watched` is a `Simulator` itself, and only derefs to the wrapped simulator immutably, so that no
+/// step goes unwatched. The watch closures run after every timestep, including while settling. The
+/// checked stepping functions stop as soon as a breakpoint fires, while `step_until_settled` stops
+/// early and leaves the breakpoint to be reported by the next checked one. Breakpoints that fire in
+/// the same step are reported one at a time, by as many calls. Watched buses are checked whenever
+/// `settle`, `clock_edge` or `run_cycles` has let the circuit settle.
+pub struct Watched<S> {
+    sim: S,
+    watches: Watches,
+    fired: VecDeque<Stop>,
+}
+
+impl<S: Simulator> Watched<S> {
+    pub fn new(sim: S) -> Self {
+        Watched { sim, watches: Watches::default(), fired: VecDeque::new() }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sim
+    }
+
+    /// Adds a breakpoint on a named signal or bus, such as "pc == 0x0e", "w rises" or
+    /// "spi_cs falls". Returns an ID for `remove_watch`.
+    pub fn add_breakpoint(&mut self, condition: &str) -> Result<usize, WatchError> {
+        let sim = &self.sim;
+        self.watches.add_breakpoint(sim.netlist(), condition, |index| sim.gate_value(index))
+    }
+
+    /// Runs a closure with the new value every time a named signal or bus changes. Returns an ID
+    /// for `remove_watch`.
+    pub fn watch(&mut self, name: &str, f: impl FnMut(u64) + 'static) -> Result<usize, WatchError> {
+        let sim = &self.sim;
+        self.watches.add_watcher(sim.netlist(), name, |index| sim.gate_value(index), Box::new(f))
+    }
+
+    /// Stops with `Stop::Bus` when the bus of a monitor is contended or floating after the circuit
+    /// settles. Returns an ID for `remove_watch`.
+    pub fn watch_bus(&mut self, monitor: &BusMonitor) -> Result<usize, WatchError> {
+        self.watches.add_bus(self.sim.netlist(), monitor)
+    }
+
+    /// Removes a breakpoint, watch closure or watched bus, along with its stops not reported yet.
+    /// Returns false if there was none with the ID.
+    pub fn remove_watch(&mut self, id: usize) -> bool {
+        self.fired.retain(|stop| !matches!(stop, Stop::Breakpoint { id: fired, .. } if *fired == id));
+        self.watches.remove(id)
+    }
+
+    /// Runs the simulation for one timestep. Stops with the breakpoint if one fired, without stepping
+    /// if one is still to be reported.
+    pub fn step_checked(&mut self) -> Result<(), Stop> {
+        next_stop(&mut self.fired)?;
+        self.sim.step();
+        check(&mut self.watches, &mut self.fired, &self.sim)
+    }
+
+    /// Runs the simulation for n timesteps, or until a breakpoint fires
+    pub fn step_by_checked(&mut self, steps: usize) -> Result<(), Stop> {
+        for _ in 0..steps {
+            self.step_checked()?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the simulation until it settles, like `step_until_settled`, or until a breakpoint
+    /// fires. Also finishes a clock edge that was stopped by a breakpoint.
+    pub fn settle(&mut self, max_steps: usize) -> Result<usize, Stop> {
+        next_stop(&mut self.fired)?;
+
+        for i in 1..=max_steps {
+            let settled = self.sim.step_until_settled(1).is_some();
+            check(&mut self.watches, &mut self.fired, &self.sim)?;
+
+            if settled {
+                check_buses(&self.watches, &self.sim)?;
+                return Ok(i);
+            }
+        }
+
+        Err(Stop::NotSettled { cycle: self.sim.cycles() })
+    }
+
+    /// Like `Simulator::clock_edge`, but stops in the middle of the edge when a breakpoint fires,
+    /// and checks the watched buses once it settles
+    pub fn clock_edge(&mut self) -> Result<(), Stop> {
+        next_stop(&mut self.fired)?;
+
+        let (watches, fired) = (&mut self.watches, &mut self.fired);
+        self.sim.clock_edge_with(|sim| check(watches, fired, sim))?;
+        check_buses(&self.watches, &self.sim)
+    }
+
+    /// Like `Simulator::run_cycles`, but stops as soon as a breakpoint fires or a watched bus fails
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), Stop> {
+        for _ in 0..cycles {
+            self.clock_edge()?;
+            self.clock_edge()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the watch closures of the signals that changed since the last check, and queues the
+/// breakpoints that fired
+fn watch<S: Simulator>(watches: &mut Watches, fired: &mut VecDeque<Stop>, sim: &S) {
+    if watches.is_empty() {
+        return;
+    }
+
+    let cycle = sim.cycles();
+
+    for (id, condition, value) in watches.check(|index| sim.gate_value(index)) {
+        fired.push_back(Stop::Breakpoint { id, condition, value, cycle });
+    }
+}
+
+/// Stops with the first breakpoint that fired and hasn't been reported yet
+fn next_stop(fired: &mut VecDeque<Stop>) -> Result<(), Stop> {
+    fired.pop_front().map_or(Ok(()), Err)
+}
+
+/// Runs the watch closures, and stops with the first breakpoint that fired
+fn check<S: Simulator>(watches: &mut Watches, fired: &mut VecDeque<Stop>, sim: &S) -> Result<(), Stop> {
+    watch(watches, fired, sim);
+    next_stop(fired)
+}
+
+/// Stops with the first watched bus that is contended or floating
+fn check_buses<S: Simulator>(watches: &Watches, sim: &S) -> Result<(), Stop> {
+    match watches.check_buses(|index| sim.gate_value(index)) {
+        Some((id, fault)) => Err(Stop::Bus { id, fault, cycle: sim.cycles() }),
+        None => Ok(()),
+    }
+}
+
+impl<S: Simulator> Simulator for Watched<S> {
+    fn with_optimizer(gates: &[Gate], config: &OptimizerConfig) -> Self {
+        Watched::new(S::with_optimizer(gates, config))
+    }
+
+    fn set(&mut self, input: &Input, bits: impl Into<u64>) {
+        self.sim.set(input, bits);
+    }
+
+    fn get<R: TryFrom<u64>>(&self, output: &Output) -> R
+        where <R as TryFrom<u64>>::Error: std::fmt::Debug
+    {
+        self.sim.get(output)
+    }
+
+    /// Runs one timestep and the watch closures. A breakpoint that fires is reported by the next
+    /// checked stepping function.
+    fn step(&mut self) {
+        self.sim.step();
+        watch(&mut self.watches, &mut self.fired, &self.sim);
+    }
+
+    /// Stops early, returning None, when a breakpoint fires or one is still to be reported
+    fn step_until_settled(&mut self, max_steps: usize) -> Option<usize> {
+        for i in 1..=max_steps {
+            if !self.fired.is_empty() {
+                return None;
+            }
+
+            let settled = self.sim.step_until_settled(1).is_some();
+            watch(&mut self.watches, &mut self.fired, &self.sim);
+
+            if settled && self.fired.is_empty() {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn snapshot(&mut self) {
+        self.sim.snapshot();
+    }
+
+    fn save_state_bytes_with(&self, traces: bool) -> Vec<u8> {
+        self.sim.save_state_bytes_with(traces)
+    }
+
+    /// Restores a saved state of the wrapped simulator, and drops the breakpoints not reported yet
+    fn load_state_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sim.load_state_bytes(bytes)?;
+        self.fired.clear();
+        Ok(())
+    }
+
+    fn truncate_snapshots(&mut self, snapshots: usize) {
+        self.sim.truncate_snapshots(snapshots);
+    }
+
+    fn show(&self) {
+        self.sim.show();
+    }
+
+    fn num_gates(&self) -> usize {
+        self.sim.num_gates()
+    }
+
+    fn clock(&self) -> &Clock {
+        self.sim.clock()
+    }
+
+    fn clock_mut(&mut self) -> &mut Clock {
+        self.sim.clock_mut()
+    }
+
+    fn netlist(&self) -> &Netlist {
+        self.sim.netlist()
+    }
+
+    fn gate_value(&self, index: usize) -> u8 {
+        self.sim.gate_value(index)
+    }
+
+    fn changed_gates(&self) -> Option<&[u32]> {
+        self.sim.changed_gates()
+    }
+
+    fn force_gate(&mut self, index: usize, value: Option<u8>) {
+        self.sim.force_gate(index, value);
+    }
+}
+
+impl<S> Deref for Watched<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.sim
+    }
+}
+
+fn parse_value(s: &str) -> Option<u64> {
+    let s = s.trim();
+
+    if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::modules::testing::cpu_system;
+    use crate::modules::*;
+    use crate::simulator::*;
+
+    #[test]
+    fn test_breakpoints() {
+        let ((clk_i, rst_i), mut sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (clk_i, clk) = input(1);
+            let (rst_i, rst) = input(1);
+            let q = vv(4);
+            let m = latch(increment(q), one(), clk.at(0), !rst.at(0));
+            q << latch(m, one(), !clk.at(0), !rst.at(0));
+            q.name("q").output();
+            (clk_i, rst_i)
+        });
+
+        sim.set_clock(&clk_i);
+        sim.set(&rst_i, 1u8);
+        sim.step_until_settled(100).unwrap();
+        sim.set(&rst_i, 0u8);
+        sim.step_until_settled(100).unwrap();
+
+        let mut sim = Watched::new(sim);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let s = seen.clone();
+        sim.watch("q", move |v| s.borrow_mut().push(v)).unwrap();
+
+        let id = sim.add_breakpoint("q == 0x3").unwrap();
+        match sim.run_cycles(10) {
+            Err(Stop::Breakpoint { id: fired, value: 3, .. }) => assert_eq!(fired, id),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // the counter passes through 3 on its way from 1 to 2, and stops there mid-edge
+        assert!(seen.borrow().ends_with(&[1, 3]));
+        assert!(sim.settle(100).is_ok());
+        assert_eq!(*seen.borrow().last().unwrap(), 2);
+        assert!(sim.remove_watch(id));
+
+        let id = sim.add_breakpoint("q 2 falls").unwrap();
+        assert!(matches!(sim.run_cycles(10), Err(Stop::Breakpoint { value: 0, .. })));
+        assert!(sim.remove_watch(id));
+        assert_eq!(*seen.borrow().last().unwrap(), 8);
+
+        assert_eq!(sim.add_breakpoint("nope rises"), Err(WatchError::UnknownSignal("nope".into())));
+        assert_eq!(sim.add_breakpoint("q"), Err(WatchError::Syntax("q".into())));
+        assert_eq!(sim.add_breakpoint("q == x"), Err(WatchError::Syntax("q == x".into())));
+    }
+
+    #[test]
+    fn test_breakpoint_while_settling() {
+        let ((a_i, en_i), sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (en_i, en) = input(1);
+            let b = nand(a.at(0), en.at(0));
+            let c = nand(b, en.at(0));
+            nand(c, en.at(0)).name("d").output();
+            (a_i, en_i)
+        });
+
+        let mut sim = Watched::new(sim);
+        sim.set(&en_i, 1u8);
+        sim.settle(100).unwrap();
+
+        sim.add_breakpoint("d changes").unwrap();
+        sim.set(&a_i, 1u8);
+        sim.step_by_checked(2).unwrap();
+        assert!(matches!(sim.step_checked(), Err(Stop::Breakpoint { value: 0, .. })));
+
+        sim.set(&a_i, 0u8);
+        assert!(matches!(sim.settle(100), Err(Stop::Breakpoint { value: 1, .. })));
+        assert!(sim.settle(100).is_ok());
+        assert_eq!(sim.probe("d"), Ok(1));
+    }
+
+    #[test]
+    fn test_breakpoints_not_skipped() {
+        let ((a_i, en_i), sim): (_, ChangeListSimulator) = build_simulator(|| {
+            let (a_i, a) = input(1);
+            let (en_i, en) = input(1);
+            let b = nand(a.at(0), en.at(0));
+            nand(b, en.at(0)).name("c").output();
+            (a_i, en_i)
+        });
+
+        let mut sim = Watched::new(sim);
+        sim.set(&en_i, 1u8);
+        sim.settle(100).unwrap();
+
+        let rises = sim.add_breakpoint("c rises").unwrap();
+        let changes = sim.add_breakpoint("c changes").unwrap();
+
+        // both breakpoints fire in the same step, and are reported one after the other
+        sim.set(&a_i, 1u8);
+        match sim.settle(100) {
+            Err(Stop::Breakpoint { id, value: 1, .. }) => assert_eq!(id, rises),
+            r => panic!("unexpected {:?}", r),
+        }
+        match sim.settle(100) {
+            Err(Stop::Breakpoint { id, value: 1, .. }) => assert_eq!(id, changes),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(sim.settle(100).is_ok());
+
+        // stepping through the Simulator functions keeps the breakpoints for the next checked call
+        sim.remove_watch(rises);
+        sim.set(&a_i, 0u8);
+        assert_eq!(sim.step_until_settled(100), None);
+        assert!(matches!(sim.step_checked(), Err(Stop::Breakpoint { value: 0, .. })));
+        assert!(sim.settle(100).is_ok());
+        assert_eq!(sim.probe("c"), Ok(0));
+    }
+
+    #[test]
+    fn test_probe() {
+        let (io, mut sim): (_, LevelizedSimulator) = build_simulator(cpu_system);
+
+        sim.set(&io.rst, 1u8);
+        sim.step_until_settled(1000).unwrap();
+        sim.set(&io.rst, 0u8);
+        sim.set_clock(&io.clk);
+
+        let mut writes = 0;
+
+        for _ in 0..40 {
+            sim.run_cycles(1).unwrap();
+
+            let pc = sim.probe_bus("pc").unwrap();
+            assert_eq!(sim.probe("pc 0").unwrap() as u64, pc & 1);
+            assert!(pc < 8);
+
+            if sim.get::<u8>(&io.w) == 1 {
+                assert_eq!(sim.probe_bus("r0").unwrap(), sim.get::<u64>(&io.data));
+                writes += 1;
+            }
+        }
+
+        assert!(writes > 0);
+        assert_eq!(sim.probe("pc"), Err(WatchError::UnknownSignal("pc".into())));
+        assert_eq!(sim.probe_bus("nope"), Err(WatchError::UnknownSignal("nope".into())));
+    }
+}